@@ -11,7 +11,9 @@ fn main() {
 
     impl CustomizeCallback for GenSerde {
         fn message(&self, _message: &MessageDescriptor) -> Customize {
-            Customize::default().before("#[derive(::serde::Serialize, ::serde::Deserialize)]")
+            // missing fields are filled with the defaults, so new fields can be added to the messages.
+            Customize::default()
+                .before("#[derive(::serde::Serialize, ::serde::Deserialize)]\n#[serde(default)]")
         }
 
         fn field(&self, field: &FieldDescriptor) -> Customize {
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use rocksdb::backup::{BackupEngine, BackupEngineOptions, RestoreOptions};
+use rocksdb::Env;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::db::{DBInstance, RocksDB};
+use super::errors::ConstDBError;
+use super::history::now_millis;
+use super::Id;
+use crate::utils;
+
+/// the file listing the backups of the server
+const MANIFEST_FILE: &str = "backups.json";
+
+/// a backup of all the databases of the server, along with the ids of the backups of the
+/// databases
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ServerBackup {
+    pub id: u32,
+    /// milliseconds since the epoch
+    pub timestamp: u64,
+    pub dbs: BTreeMap<String, u32>,
+}
+
+/// the backups of the server. every database is backed up incrementally in a directory of its own
+/// by the backup engine of rocksdb, and the backups of the server are listed in the manifest.
+pub struct Backups {
+    dir: PathBuf,
+    /// a backup engine should not be used by more than one at a time
+    lock: Mutex<()>,
+}
+
+impl Backups {
+    pub fn new(dir: &str) -> Self {
+        Backups {
+            dir: PathBuf::from(dir),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// back up all the databases, the memtables are flushed first
+    pub fn create(&self, dbs: &[&DBInstance]) -> Result<ServerBackup, ConstDBError> {
+        let _guard = self.lock()?;
+        let mut backups = self.read_manifest()?;
+        let mut backup = ServerBackup {
+            id: backups.last().map_or(1, |b| b.id + 1),
+            timestamp: now_millis(),
+            dbs: BTreeMap::new(),
+        };
+        for db in dbs {
+            // the databases without any table have nothing to back up
+            if let Some(rocks_db) = db.rocks_db.as_deref() {
+                let db_backup_id = self.backup_db(db.name.as_str(), rocks_db)?;
+                backup.dbs.insert(db.name.to_owned(), db_backup_id);
+            }
+        }
+        backups.push(backup.clone());
+        self.write_manifest(&backups)?;
+        Ok(backup)
+    }
+
+    fn backup_db(&self, db_name: &str, rocks_db: &RocksDB) -> Result<u32, ConstDBError> {
+        // the backup engine creates the directory of the db but not its parents
+        std::fs::create_dir_all(self.db_dir(db_name))?;
+        let mut engine = self.engine(db_name)?;
+        engine.create_new_backup_flush(rocks_db, true)?;
+        engine
+            .get_backup_info()
+            .iter()
+            .map(|info| info.backup_id)
+            .max()
+            .ok_or_else(|| {
+                ConstDBError::InvalidStates(format!("cannot find backup of db [{}]", db_name))
+            })
+    }
+
+    pub fn list(&self) -> Result<Vec<ServerBackup>, ConstDBError> {
+        let _guard = self.lock()?;
+        self.read_manifest()
+    }
+
+    pub fn get(&self, id: u32) -> Result<ServerBackup, ConstDBError> {
+        self.list()?
+            .into_iter()
+            .find(|b| b.id == id)
+            .ok_or(ConstDBError::NotFound(Id::Backup(id)))
+    }
+
+    pub fn latest(&self) -> Result<ServerBackup, ConstDBError> {
+        self.list()?.pop().ok_or_else(|| {
+            ConstDBError::InvalidArguments(format!("no backup found in {}", self.dir.display()))
+        })
+    }
+
+    /// purge the old backups and keep the latest `keep` ones, returns the backups kept
+    pub fn purge(&self, keep: usize) -> Result<Vec<ServerBackup>, ConstDBError> {
+        let _guard = self.lock()?;
+        let mut backups = self.read_manifest()?;
+        let purged: Vec<ServerBackup> = backups
+            .drain(..backups.len().saturating_sub(keep))
+            .collect();
+        // the backups of a database are in the same order as the backups of the server, so the
+        // latest ones of the database are the ones kept
+        let mut kept_by_db = BTreeMap::new();
+        for backup in &backups {
+            for db_name in backup.dbs.keys() {
+                *kept_by_db.entry(db_name.as_str()).or_insert(0) += 1;
+            }
+        }
+        for db_name in purged.iter().flat_map(|b| b.dbs.keys()) {
+            match kept_by_db.get(db_name.as_str()) {
+                Some(kept) => self.engine(db_name)?.purge_old_backups(*kept)?,
+                None if utils::fs::exists(self.db_dir(db_name))? => {
+                    std::fs::remove_dir_all(self.db_dir(db_name))?
+                }
+                None => {}
+            }
+        }
+        self.write_manifest(&backups)?;
+        Ok(backups)
+    }
+
+    /// restore the database of the backup to the directory, a directory left over by an earlier
+    /// restore is replaced. the directory is removed if the restore fails.
+    pub fn restore_db(
+        &self,
+        backup: &ServerBackup,
+        db_name: &str,
+        path: &Path,
+    ) -> Result<(), ConstDBError> {
+        let _guard = self.lock()?;
+        let db_backup_id = *backup.dbs.get(db_name).ok_or_else(|| {
+            ConstDBError::InvalidArguments(format!(
+                "db [{}] is not found in backup {}",
+                db_name, backup.id
+            ))
+        })?;
+        utils::fs::remove_dir(path)?;
+        let result = self.engine(db_name).and_then(|mut engine| {
+            engine.restore_from_backup(path, path, &RestoreOptions::default(), db_backup_id)?;
+            Ok(())
+        });
+        if result.is_err() {
+            utils::fs::remove_dir(path)?;
+        }
+        result
+    }
+
+    /// restore all the databases of the backup to the root, the server should not be running. the
+    /// databases are restored next to their files, which are replaced only once all of them are
+    /// restored. the databases not in the backup are removed.
+    pub fn restore_server(&self, backup: &ServerBackup, root: &Path) -> Result<(), ConstDBError> {
+        let mut restored = Vec::new();
+        let result = backup.dbs.keys().try_for_each(|db_name| {
+            let path = root.join(db_name);
+            std::fs::create_dir_all(&path)?;
+            let rocks_db_path = DBInstance::rocks_db_path(&path);
+            let restoring_path = utils::fs::sibling(&rocks_db_path, "restoring");
+            self.restore_db(backup, db_name, &restoring_path)?;
+            restored.push((restoring_path, rocks_db_path));
+            Ok(())
+        });
+        if let Err(e) = result {
+            for (restoring_path, _) in restored {
+                utils::fs::remove_dir(restoring_path)?;
+            }
+            return Err(e);
+        }
+        for entry in std::fs::read_dir(root)? {
+            let path = entry?.path();
+            let in_backup = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => backup.dbs.contains_key(name),
+                None => false,
+            };
+            if !in_backup && utils::fs::exists(DBInstance::rocks_db_path(&path))? {
+                std::fs::remove_dir_all(path)?;
+            }
+        }
+        for (restoring_path, rocks_db_path) in restored {
+            utils::fs::replace_dir(restoring_path, rocks_db_path)?;
+        }
+        Ok(())
+    }
+
+    /// restore the system db of the backup to a temporary directory of its own, and pass it to `f`
+    pub fn with_system_db<F, T>(&self, backup: &ServerBackup, f: F) -> Result<T, ConstDBError>
+    where
+        F: FnOnce(&RocksDB) -> Result<T, ConstDBError>,
+    {
+        let path = self
+            .dir
+            .join(format!("restoring-{}", Uuid::new_v4().simple()));
+        self.restore_db(backup, "system", &path)?;
+        let result = RocksDB::open_default(&path)
+            .map_err(ConstDBError::from)
+            .and_then(|rocks_db| f(&rocks_db));
+        utils::fs::remove_dir(&path)?;
+        result
+    }
+
+    /// the directory of the backups of the database
+    fn db_dir(&self, db_name: &str) -> PathBuf {
+        self.dir.join("dbs").join(db_name)
+    }
+
+    fn engine(&self, db_name: &str) -> Result<BackupEngine, ConstDBError> {
+        let opts = BackupEngineOptions::new(self.db_dir(db_name))?;
+        Ok(BackupEngine::open(&opts, &Env::new()?)?)
+    }
+
+    fn read_manifest(&self) -> Result<Vec<ServerBackup>, ConstDBError> {
+        let path = self.dir.join(MANIFEST_FILE);
+        match utils::fs::exists(&path)? {
+            true => Ok(serde_json::from_slice(&std::fs::read(path)?)?),
+            false => Ok(Vec::new()),
+        }
+    }
+
+    /// the manifest is replaced as a whole, so that it is never seen half written
+    fn write_manifest(&self, backups: &[ServerBackup]) -> Result<(), ConstDBError> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(MANIFEST_FILE);
+        let tmp_path = self.dir.join(format!("{}.tmp", MANIFEST_FILE));
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(backups)?)?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, ()>, ConstDBError> {
+        self.lock
+            .lock()
+            .map_err(|_| ConstDBError::InvalidStates("backups lock poisoned".to_owned()))
+    }
+}
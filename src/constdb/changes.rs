@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use rocksdb::{ColumnFamily, Transaction};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::watch;
+
+use super::db::RocksDB;
+use super::errors::ConstDBError;
+use super::history::now_millis;
+use super::PrimaryKey;
+
+/// a change of a row, the images of the row before and after the change are `None` if the row
+/// does not exist
+#[derive(Serialize, Deserialize)]
+pub struct Change {
+    /// the sequence number of the change within the database
+    pub seq: u64,
+    /// milliseconds since the epoch the change is committed at
+    pub ts: u64,
+    pub table: String,
+    pub op: ChangeOp,
+    /// the primary key fields of the row
+    pub key: Map<String, Value>,
+    pub before: Option<Map<String, Value>>,
+    pub after: Option<Map<String, Value>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// the changes are keyed by their sequence numbers in big endian, so they are in the order of
+/// the sequence numbers
+pub fn change_key(seq: u64) -> [u8; 8] {
+    seq.to_be_bytes()
+}
+
+pub fn decode_change_key(key: &[u8]) -> Result<u64, ConstDBError> {
+    key.try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| ConstDBError::InvalidStates("invalid key of change log".to_owned()))
+}
+
+/// the log of the changes of the rows of a database. the sequence numbers and times of the
+/// changes are taken when their transaction is committed, and the changes are read only up to
+/// the sequence number all the commits before which are done, so that a reader never skips a
+/// change committed later with a smaller sequence number. the sequence numbers of the failed
+/// commits are skipped.
+pub struct ChangeLog {
+    sequence: Mutex<Sequence>,
+    /// notified with the last sequence number committed whenever it moves
+    notifier: watch::Sender<u64>,
+}
+
+#[derive(Default)]
+struct Sequence {
+    /// the last sequence number taken by a commit
+    taken: u64,
+    /// all the commits up to this sequence number are done
+    committed: u64,
+    /// the first and last sequence numbers of the commits done before an earlier one
+    done: BTreeMap<u64, u64>,
+    /// the time of the last change taken, so that the times follow the sequence numbers
+    last_ts: u64,
+    /// the changes up to this sequence number are pruned
+    pruned: u64,
+}
+
+impl Sequence {
+    /// take the sequence numbers of `count` changes, returns the first one along with the time
+    fn take(&mut self, count: u64, now: u64) -> (u64, u64) {
+        let first_seq = self.taken + 1;
+        self.taken += count;
+        self.last_ts = self.last_ts.max(now);
+        (first_seq, self.last_ts)
+    }
+
+    /// mark the sequence numbers of a commit as done, returns the new last sequence number
+    /// committed if it moves
+    fn finish(&mut self, first_seq: u64, last_seq: u64) -> Option<u64> {
+        self.done.insert(first_seq, last_seq);
+        let committed = self.committed;
+        while let Some(last_seq) = self.done.remove(&(self.committed + 1)) {
+            self.committed = last_seq;
+        }
+        (self.committed > committed).then_some(self.committed)
+    }
+}
+
+impl Default for ChangeLog {
+    fn default() -> Self {
+        ChangeLog {
+            sequence: Mutex::new(Sequence::default()),
+            notifier: watch::channel(0).0,
+        }
+    }
+}
+
+impl ChangeLog {
+    /// continue the log after the last change found in the db, the changes before the first one
+    /// found are taken as pruned
+    pub fn reset(&self, pruned: u64, last_seq: u64) -> Result<(), ConstDBError> {
+        *self.lock()? = Sequence {
+            taken: last_seq,
+            committed: last_seq,
+            pruned,
+            ..Sequence::default()
+        };
+        self.notifier.send_replace(last_seq);
+        Ok(())
+    }
+
+    /// the sequence number all the commits up to which are done
+    pub fn last_seq(&self) -> Result<u64, ConstDBError> {
+        Ok(self.lock()?.committed)
+    }
+
+    /// receive the last sequence number whenever changes are committed
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.notifier.subscribe()
+    }
+
+    /// mark the changes up to the sequence number as pruned, before they are deleted
+    pub fn prune(&self, seq: u64) -> Result<(), ConstDBError> {
+        let mut sequence = self.lock()?;
+        sequence.pruned = sequence.pruned.max(seq);
+        Ok(())
+    }
+
+    /// check that none of the changes after `since` is pruned
+    pub fn check_since(&self, since: u64) -> Result<(), ConstDBError> {
+        let pruned = self.lock()?.pruned;
+        match since < pruned {
+            true => Err(ConstDBError::Gone(format!(
+                "history truncated: changes since {} are pruned, the first change kept is after {}",
+                since, pruned
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    /// number the changes and commit them along with the transaction
+    pub fn commit(
+        &self,
+        txn: Transaction<'_, RocksDB>,
+        cf: &ColumnFamily,
+        changes: Vec<Change>,
+    ) -> Result<(), ConstDBError> {
+        let (first_seq, ts) = self.lock()?.take(changes.len() as u64, now_millis());
+        let last_seq = first_seq + changes.len() as u64 - 1;
+        let result = changes
+            .into_iter()
+            .zip(first_seq..)
+            .try_for_each(|(mut change, seq)| {
+                change.seq = seq;
+                change.ts = ts;
+                txn.put_cf(cf, change_key(seq), serde_json::to_vec(&change)?)?;
+                Ok(())
+            })
+            .and_then(|_| Ok(txn.commit()?));
+        // the sequence numbers are done even if the commit fails, so that the later ones are read
+        if let Some(committed) = self.lock()?.finish(first_seq, last_seq) {
+            self.notifier.send_replace(committed);
+        }
+        result
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Sequence>, ConstDBError> {
+        self.sequence
+            .lock()
+            .map_err(|_| ConstDBError::InvalidStates("change log lock poisoned".to_owned()))
+    }
+}
+
+/// a watch of the rows of a table by the primary key, or a prefix or a range of it
+pub struct RowWatch {
+    pub pk: PrimaryKey,
+    /// the sequence number of the change log to watch the changes after
+    pub since: u64,
+    pub timeout: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commits_are_read_once_the_earlier_ones_are_done() {
+        let mut sequence = Sequence::default();
+        assert_eq!(sequence.take(2, 100), (1, 100));
+        assert_eq!(sequence.take(1, 90), (3, 100));
+        assert_eq!(sequence.take(1, 110), (4, 110));
+        assert_eq!(sequence.finish(3, 3), None);
+        assert_eq!(sequence.finish(4, 4), None);
+        assert_eq!(sequence.committed, 0);
+        assert_eq!(sequence.finish(1, 2), Some(4));
+        assert!(sequence.done.is_empty());
+    }
+
+    #[test]
+    fn changes_since_the_pruned_ones_are_gone() {
+        let log = ChangeLog::default();
+        log.reset(0, 10).unwrap();
+        log.check_since(0).unwrap();
+        log.prune(5).unwrap();
+        assert!(matches!(log.check_since(0), Err(ConstDBError::Gone(_))));
+        assert!(matches!(log.check_since(4), Err(ConstDBError::Gone(_))));
+        log.check_since(5).unwrap();
+    }
+}
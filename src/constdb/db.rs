@@ -1,16 +1,39 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, Options, DB};
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, IteratorMode, OptimisticTransactionDB, Options,
+    ReadOptions, WriteBatchWithTransaction, DB,
+};
 
 use crate::protos::constdb_model::TableSettings;
 
+use super::changes::{decode_change_key, ChangeLog};
 use super::errors::ConstDBError;
+use super::row::merge_row_patches;
+use super::snapshot::{DBSnapshot, HeldSnapshot, Snapshots};
 use crate::utils;
 
+/// the rows are written in optimistic transactions, which fail on commit if any of the keys read
+/// by the transaction is written by others in the meantime.
+pub type RocksDB = OptimisticTransactionDB;
+pub type WriteBatch = WriteBatchWithTransaction<true>;
+
+/// the name of the merge operator of the tables, which applies the patches of upserts
+const ROW_MERGE_OPERATOR: &str = "constdb.row_patch";
+
+/// the column family of the change log of the db, which never clashes with a table as `#` is not
+/// allowed in the names of tables
+const CHANGE_LOG_CF: &str = "#changes";
+
 pub struct DBInstance {
     pub name: String,
     pub root: String,
-    pub rocks_db: Option<DB>,
+    /// shared with the snapshots of the db, which borrow it
+    pub rocks_db: Option<Arc<RocksDB>>,
+    pub snapshots: Snapshots,
+    pub changes: ChangeLog,
 }
 
 impl DBInstance {
@@ -19,27 +42,186 @@ impl DBInstance {
             name: name.to_owned(),
             root: root.to_owned(),
             rocks_db: None,
+            snapshots: Snapshots::default(),
+            changes: ChangeLog::default(),
         }
     }
 
-    pub fn rocks_db(&self) -> Result<&DB, ConstDBError> {
+    /// the directory of the rocks db of the db of the root
+    pub fn rocks_db_path(root: &Path) -> PathBuf {
+        root.join("bin.db")
+    }
+
+    pub fn rocks_db(&self) -> Result<&RocksDB, ConstDBError> {
         self.rocks_db
-            .as_ref()
+            .as_deref()
             .ok_or_else(|| ConstDBError::InvalidStates("rocks db not initialized!".to_owned()))
     }
 
+    /// the db to create or drop the column families, which is not allowed while the snapshots of
+    /// the db borrow it. the snapshots held by the scans are released instead.
+    fn rocks_db_mut(&mut self) -> Result<&mut RocksDB, ConstDBError> {
+        self.check_no_snapshots()?;
+        // the scans go on at new snapshots after the change
+        self.snapshots.release_held()?;
+        let rocks_db = self
+            .rocks_db
+            .as_mut()
+            .ok_or_else(|| ConstDBError::InvalidStates("rocks db not initialized!".to_owned()))?;
+        Arc::get_mut(rocks_db).ok_or_else(|| {
+            ConstDBError::Conflict(format!("db [{}] is being read, try again later", self.name))
+        })
+    }
+
+    /// check that no snapshot of the db is open, before the db is changed in a way that the
+    /// snapshots cannot be read at
+    pub fn check_no_snapshots(&self) -> Result<(), ConstDBError> {
+        self.snapshots.reap()?;
+        let snapshots = self.snapshots.len()?;
+        match snapshots {
+            0 => Ok(()),
+            _ => Err(ConstDBError::Conflict(format!(
+                "db [{}] has {} open snapshots, release them or wait for them to expire first",
+                self.name, snapshots
+            ))),
+        }
+    }
+
+    /// check that the column families can be created or dropped, before a change of the tables
+    /// that creates or drops any of them
+    pub fn check_exclusive(&mut self) -> Result<(), ConstDBError> {
+        match self.rocks_db {
+            Some(_) => self.rocks_db_mut().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// take a snapshot of the db, returns the token of the snapshot
+    pub fn create_snapshot(&self, ttl: Duration) -> Result<String, ConstDBError> {
+        let rocks_db = self
+            .rocks_db
+            .as_ref()
+            .ok_or_else(|| ConstDBError::InvalidStates("rocks db not initialized!".to_owned()))?;
+        self.snapshots.create(Arc::clone(rocks_db), ttl)
+    }
+
+    /// the snapshot of the token to read at, or `None` to read the latest data
+    pub fn snapshot(&self, token: Option<&str>) -> Result<Option<Arc<DBSnapshot>>, ConstDBError> {
+        token.map(|token| self.snapshots.get(token)).transpose()
+    }
+
+    /// hold a snapshot for a scan, at the snapshot of the token if given
+    pub fn hold_snapshot(&self, token: Option<&str>) -> Result<HeldSnapshot, ConstDBError> {
+        let rocks_db = self
+            .rocks_db
+            .as_ref()
+            .ok_or_else(|| ConstDBError::InvalidStates("rocks db not initialized!".to_owned()))?;
+        self.snapshots
+            .hold(Arc::clone(rocks_db), self.snapshot(token)?)
+    }
+
+    /// the snapshot held for a scan, which is taken again if it is released
+    pub fn held_snapshot(&self, held: &HeldSnapshot) -> Result<Arc<DBSnapshot>, ConstDBError> {
+        let rocks_db = self
+            .rocks_db
+            .as_ref()
+            .ok_or_else(|| ConstDBError::InvalidStates("rocks db not initialized!".to_owned()))?;
+        self.snapshots.held(held, rocks_db)
+    }
+
+    /// read options at the snapshot, the snapshot should be kept until the reads are done
+    pub fn read_options(snapshot: Option<&DBSnapshot>) -> ReadOptions {
+        let mut read_opts = ReadOptions::default();
+        if let Some(snapshot) = snapshot {
+            snapshot.set_on(&mut read_opts);
+        }
+        read_opts
+    }
+
     pub fn rocks_db_for_table(&self, table_name: &str) -> Result<&ColumnFamily, ConstDBError> {
         self.rocks_db()?.cf_handle(table_name).ok_or_else(|| {
             ConstDBError::InvalidStates(format!("cannot find table for {}", table_name))
         })
     }
 
+    pub fn rocks_db_for_index(
+        &self,
+        table_name: &str,
+        index_name: &str,
+    ) -> Result<&ColumnFamily, ConstDBError> {
+        let cf_name = Self::index_cf_name(table_name, index_name);
+        self.rocks_db()?.cf_handle(cf_name.as_str()).ok_or_else(|| {
+            ConstDBError::InvalidStates(format!("cannot find index for {}", cf_name))
+        })
+    }
+
+    /// the column family of a secondary index, `#` is not allowed in the names of tables
+    fn index_cf_name(table_name: &str, index_name: &str) -> String {
+        format!("{}#{}", table_name, index_name)
+    }
+
+    pub fn rocks_db_for_row_history(
+        &self,
+        table_name: &str,
+    ) -> Result<&ColumnFamily, ConstDBError> {
+        let cf_name = Self::row_history_cf_name(table_name);
+        self.rocks_db()?.cf_handle(cf_name.as_str()).ok_or_else(|| {
+            ConstDBError::InvalidStates(format!("cannot find row history for {}", cf_name))
+        })
+    }
+
+    /// the column family of the previous versions of the rows, the names of indexes cannot be
+    /// empty, so it never clashes with the column family of an index
+    fn row_history_cf_name(table_name: &str) -> String {
+        format!("{}##history", table_name)
+    }
+
+    /// the column family the re-keyed rows of a table are staged in while the keys of the table
+    /// are migrated, `None` if the table is not being migrated
+    pub fn rocks_db_for_rekeying(
+        &self,
+        table_name: &str,
+    ) -> Result<Option<&ColumnFamily>, ConstDBError> {
+        Ok(self
+            .rocks_db()?
+            .cf_handle(Self::rekeying_cf_name(table_name).as_str()))
+    }
+
+    fn rekeying_cf_name(table_name: &str) -> String {
+        format!("{}##rekeying", table_name)
+    }
+
+    /// the column family of the change log, `None` if no table has ever logged its changes
+    pub fn rocks_db_for_changes(&self) -> Result<Option<&ColumnFamily>, ConstDBError> {
+        Ok(self.rocks_db()?.cf_handle(CHANGE_LOG_CF))
+    }
+
+    /// the change log is kept for all the tables of the db once created
+    pub fn create_change_log(&mut self) -> Result<(), ConstDBError> {
+        if self.rocks_db_for_changes()?.is_some() {
+            return Ok(());
+        }
+        let rocks_db = self.rocks_db_mut()?;
+        let opts = Options::default();
+        rocks_db.create_cf(CHANGE_LOG_CF, &opts)?;
+        Ok(())
+    }
+
+    /// the options of the column family, the rows of the tables are patched by the merge operator
+    fn cf_options(cf_name: &str) -> Options {
+        let mut opts = Options::default();
+        if !cf_name.contains('#') {
+            opts.set_merge_operator_associative(ROW_MERGE_OPERATOR, merge_row_patches);
+        }
+        opts
+    }
+
     pub fn create_table(&mut self, input: &TableSettings) -> Result<(), ConstDBError> {
         if self.rocks_db.is_none() {
             self.open_rocks_db()?;
         }
-        let rocks_db = self.rocks_db.as_mut().unwrap();
-        let opts = Options::default();
+        let rocks_db = self.rocks_db_mut()?;
+        let opts = Self::cf_options(input.name.as_str());
         // TODO: check if cf already exists
         rocks_db.create_cf(input.name.as_str(), &opts)?;
         Ok(())
@@ -49,30 +231,100 @@ impl DBInstance {
         if self.rocks_db.is_none() {
             return Ok(());
         }
-        let rocks_db = self.rocks_db.as_mut().unwrap();
+        let rocks_db = self.rocks_db_mut()?;
         rocks_db.drop_cf(table_name)?;
         Ok(())
     }
 
+    pub fn create_index(&mut self, table_name: &str, index_name: &str) -> Result<(), ConstDBError> {
+        let rocks_db = self.rocks_db_mut()?;
+        let opts = Options::default();
+        rocks_db.create_cf(Self::index_cf_name(table_name, index_name), &opts)?;
+        Ok(())
+    }
+
+    pub fn drop_index(&mut self, table_name: &str, index_name: &str) -> Result<(), ConstDBError> {
+        if self.rocks_db.is_none() {
+            return Ok(());
+        }
+        let rocks_db = self.rocks_db_mut()?;
+        rocks_db.drop_cf(Self::index_cf_name(table_name, index_name).as_str())?;
+        Ok(())
+    }
+
+    pub fn create_row_history(&mut self, table_name: &str) -> Result<(), ConstDBError> {
+        let rocks_db = self.rocks_db_mut()?;
+        let opts = Options::default();
+        rocks_db.create_cf(Self::row_history_cf_name(table_name), &opts)?;
+        Ok(())
+    }
+
+    pub fn drop_row_history(&mut self, table_name: &str) -> Result<(), ConstDBError> {
+        if self.rocks_db.is_none() {
+            return Ok(());
+        }
+        let rocks_db = self.rocks_db_mut()?;
+        rocks_db.drop_cf(Self::row_history_cf_name(table_name).as_str())?;
+        Ok(())
+    }
+
+    pub fn create_rekeying(&mut self, table_name: &str) -> Result<(), ConstDBError> {
+        if self.rocks_db_for_rekeying(table_name)?.is_some() {
+            return Ok(());
+        }
+        let rocks_db = self.rocks_db_mut()?;
+        let opts = Options::default();
+        rocks_db.create_cf(Self::rekeying_cf_name(table_name), &opts)?;
+        Ok(())
+    }
+
+    pub fn drop_rekeying(&mut self, table_name: &str) -> Result<(), ConstDBError> {
+        let rocks_db = self.rocks_db_mut()?;
+        rocks_db.drop_cf(Self::rekeying_cf_name(table_name).as_str())?;
+        Ok(())
+    }
+
     pub fn open_rocks_db(&mut self) -> Result<(), ConstDBError> {
-        let rocks_db_path = Path::new(self.root.as_str()).join("bin.db");
+        let rocks_db_path = Self::rocks_db_path(Path::new(self.root.as_str()));
         let opts = Options::default();
         match utils::fs::exists(&rocks_db_path)? {
             true => {
                 let cfs = DB::list_cf(&opts, rocks_db_path.clone())?
                     .into_iter()
-                    .map(|cf_name| ColumnFamilyDescriptor::new(cf_name, Options::default()));
-                self.rocks_db = Some(DB::open_cf_descriptors(&opts, rocks_db_path, cfs)?);
+                    .map(|cf_name| {
+                        let opts = Self::cf_options(cf_name.as_str());
+                        ColumnFamilyDescriptor::new(cf_name, opts)
+                    });
+                let rocks_db = RocksDB::open_cf_descriptors(&opts, rocks_db_path, cfs)?;
+                self.rocks_db = Some(Arc::new(rocks_db));
+                self.reset_change_log()?;
             }
             false => {
-                self.rocks_db = Some(DB::open_default(rocks_db_path)?);
+                self.rocks_db = Some(Arc::new(RocksDB::open_default(rocks_db_path)?));
             }
         }
         Ok(())
     }
 
+    /// continue the change log after the last change in the db
+    fn reset_change_log(&self) -> Result<(), ConstDBError> {
+        let cf = match self.rocks_db_for_changes()? {
+            Some(cf) => cf,
+            None => return self.changes.reset(0, 0),
+        };
+        let rocks_db = self.rocks_db()?;
+        let seq_at = |mode| match rocks_db.iterator_cf(cf, mode).next() {
+            Some(result_kv) => decode_change_key(&result_kv?.0).map(Some),
+            None => Ok(None),
+        };
+        match (seq_at(IteratorMode::Start)?, seq_at(IteratorMode::End)?) {
+            (Some(first_seq), Some(last_seq)) => self.changes.reset(first_seq - 1, last_seq),
+            _ => self.changes.reset(0, 0),
+        }
+    }
+
     pub fn try_open_rocks_db(&mut self) -> Result<(), ConstDBError> {
-        let rocks_db_path = Path::new(self.root.as_str()).join("bin.db");
+        let rocks_db_path = Self::rocks_db_path(Path::new(self.root.as_str()));
         if utils::fs::exists(rocks_db_path)? {
             self.open_rocks_db()?;
         }
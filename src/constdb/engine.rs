@@ -1,31 +1,89 @@
 use crate::constdb::system_db::*;
-use std::{collections::HashMap, path::Path};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 use axum::body::Bytes;
 use protobuf::Message;
-use rocksdb::{Direction, Options, ReadOptions, DB};
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{ColumnFamily, Direction, IteratorMode, Options, ReadOptions, DB};
+use tokio::sync::watch;
 
-use crate::protos::constdb_model::{DBSettings, TableSettings};
+use crate::protos::constdb_model::{DBSettings, Index, TableSettings};
+use crate::utils;
 
-use crate::constdb::{db::DBInstance, errors::ConstDBError, schema::SchemaHelper};
+use crate::constdb::db::{DBInstance, RocksDB, WriteBatch};
+use crate::constdb::snapshot::{DBSnapshot, HeldSnapshot};
+use crate::constdb::{errors::ConstDBError, schema::SchemaHelper};
 
-use super::{Id, PrimaryKey};
+use super::backup::{Backups, ServerBackup};
+use super::changes::{decode_change_key, Change, RowWatch};
+use super::history::{decode_version_key, now_millis, version_key, RowVersion};
+use super::patch::RowPatch;
+use super::scan::{encode_cursor, Page, ScanOptions};
+use super::update::RowUpdate;
+use super::writes::{CompareAndSwap, Operation, Precondition, RowWrites, UpsertMode, WriteResult};
+use super::{build_upper_bound, Id, PrimaryKey, KEY_FORMAT_VERSION};
+
+/// index entries are written in batches of this size when an index is built for existing rows
+const INDEX_BACKFILL_BATCH_SIZE: usize = 10000;
+/// rows are moved in batches of this size when the keys of a table are migrated on startup
+const KEY_MIGRATION_BATCH_SIZE: usize = 10000;
+/// a write of a single row that conflicts with a concurrent write is retried up to this many times
+const CONFLICT_RETRIES: usize = 3;
+
+/// versions of rows beyond the retention of the row history are deleted in batches of this size
+const ROW_HISTORY_PRUNE_BATCH_SIZE: usize = 10000;
+
+/// changes beyond the retention of the change log are deleted in batches of this size
+const CHANGE_LOG_PRUNE_BATCH_SIZE: usize = 10000;
+
+/// a watch waits this many seconds for the changes of the rows, unless given a timeout
+const DEFAULT_WATCH_TIMEOUT: u64 = 30;
+/// the max seconds a watch waits for the changes of the rows
+const MAX_WATCH_TIMEOUT: u64 = 300;
+
+/// snapshots are released after this many seconds without being used, unless given a ttl
+const DEFAULT_SNAPSHOT_TTL: u64 = 60;
+
+/// the keys and the values of the rows of the system db
+type SystemRows = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// all the versions of the settings of the tables by the names of the db and the table
+type TableVersions = HashMap<(String, String), Arc<Vec<TableSettings>>>;
 
 /// ConstDB settings
 pub struct Settings {
     pub root: String,
+    /// max number of rows returned by a single scan
+    pub max_page_size: usize,
+    /// max number of rows streamed by a single full table scan
+    pub max_scan_size: usize,
+    /// max seconds a snapshot is kept without being used
+    pub max_snapshot_ttl: u64,
+    /// seconds the changes of the rows are kept in the change logs
+    pub change_retention_secs: u64,
+    /// the directory of the backups, backups are not allowed without it
+    pub backup_dir: Option<String>,
 }
 
 pub struct Engine {
     dbs: HashMap<String, DBInstance>,
     settings: Settings,
+    backups: Option<Backups>,
+    /// the versions of the tables read so far, forgotten when the settings of the table change
+    table_versions: Mutex<TableVersions>,
 }
 
 impl Engine {
     pub fn new(settings: Settings) -> Result<Self, ConstDBError> {
+        let backups = settings.backup_dir.as_deref().map(Backups::new);
         let mut db = Engine {
             dbs: HashMap::new(),
             settings,
+            backups,
+            table_versions: Mutex::new(HashMap::new()),
         };
         let system_db = db.open("system")?;
 
@@ -51,9 +109,210 @@ impl Engine {
         }
 
         db.dbs.insert("system".to_owned(), system_db);
+        db.migrate_tables()?;
         Ok(db)
     }
 
+    /// version the tables created before the tables were versioned, and re-key the tables written
+    /// with an older primary key format
+    fn migrate_tables(&mut self) -> Result<(), ConstDBError> {
+        let db_names: Vec<String> = self.dbs.keys().cloned().collect();
+        for db_name in &db_names {
+            for mut table in self.list_table(db_name)? {
+                if table.version == 0 {
+                    println!("versioning table [{}.{}]...", db_name, table.name);
+                    SchemaHelper::upgrade_settings(&mut table);
+                    self.save_table_settings(db_name, &table)?;
+                }
+                if table.key_version < KEY_FORMAT_VERSION {
+                    println!(
+                        "migrating keys of table [{}.{}] from version {} to {}...",
+                        db_name, table.name, table.key_version, KEY_FORMAT_VERSION
+                    );
+                    self.migrate_table_keys(db_name, table)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// re-key the rows of the table in two passes, the rows with stale keys are first moved out of
+    /// the table to a staging column family under their new keys, and then moved back, so that a
+    /// new key never gets removed as the stale key of another row. the rows are moved in batches,
+    /// and the last key moved out is recorded in the system db to resume the migration from.
+    fn migrate_table_keys(
+        &mut self,
+        db_name: &str,
+        table: TableSettings,
+    ) -> Result<(), ConstDBError> {
+        let table_name = table.name.to_owned();
+        let history = self.table_versions(db_name, table_name.as_str())?;
+        let schema = SchemaHelper::new(table).with_history(history);
+        let migration_key = SystemKeys::table_migration_key(db_name, table_name.as_str()).as_key();
+        let resume_key = self
+            .system_db()?
+            .rocks_db()?
+            .get(migration_key.as_bytes())?;
+        self.dbs
+            .get_mut(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?
+            .create_rekeying(table_name.as_str())?;
+
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+        let rocks_db = db.rocks_db()?;
+        let cf = db.rocks_db_for_table(table_name.as_str())?;
+        let staging_cf = db
+            .rocks_db_for_rekeying(table_name.as_str())?
+            .ok_or_else(|| {
+                ConstDBError::InvalidStates(format!("cannot find staging for {}", table_name))
+            })?;
+
+        let iter_mode = match resume_key.as_deref() {
+            Some(key) => IteratorMode::From(key, Direction::Forward),
+            None => IteratorMode::Start,
+        };
+        let mut batch = WriteBatch::default();
+        let mut last_key = None;
+        for result_kv in rocks_db.iterator_cf(cf, iter_mode) {
+            let (k, v) = result_kv?;
+            let row = schema.read_row(&v)?;
+            let new_key = schema.build_pk_from_row(&row)?.complete()?.to_vec();
+            if new_key.as_slice() != k.as_ref() {
+                batch.delete_cf(cf, &k);
+                batch.put_cf(staging_cf, new_key, v);
+            }
+            last_key = Some(k);
+            if batch.len() >= KEY_MIGRATION_BATCH_SIZE {
+                rocks_db.write(std::mem::take(&mut batch))?;
+                if let Some(k) = last_key.as_deref() {
+                    self.system_db()?
+                        .rocks_db()?
+                        .put(migration_key.as_bytes(), k)?;
+                }
+            }
+        }
+        rocks_db.write(batch)?;
+        if let Some(k) = last_key.as_deref() {
+            self.system_db()?
+                .rocks_db()?
+                .put(migration_key.as_bytes(), k)?;
+        }
+
+        // moving back is resumed from what is left in the staging column family
+        let mut batch = WriteBatch::default();
+        for result_kv in rocks_db.iterator_cf(staging_cf, IteratorMode::Start) {
+            let (k, v) = result_kv?;
+            batch.put_cf(cf, &k, v);
+            batch.delete_cf(staging_cf, k);
+            if batch.len() >= KEY_MIGRATION_BATCH_SIZE {
+                rocks_db.write(std::mem::take(&mut batch))?;
+            }
+        }
+        rocks_db.write(batch)?;
+
+        self.dbs
+            .get_mut(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?
+            .drop_rekeying(table_name.as_str())?;
+        let mut table = schema.table_settings().clone();
+        table.key_version = KEY_FORMAT_VERSION;
+        self.save_table_settings(db_name, &table)?;
+        self.system_db()?
+            .rocks_db()?
+            .delete(migration_key.as_bytes())?;
+        Ok(())
+    }
+
+    /// save the table settings as the current version of the table, a copy of every version is
+    /// kept to read the rows written by it.
+    fn save_table_settings(
+        &self,
+        db_name: &str,
+        table: &TableSettings,
+    ) -> Result<(), ConstDBError> {
+        let bytes = table.write_to_bytes()?;
+        let mut batch = WriteBatch::default();
+        batch.put(
+            SystemKeys::table_meta_key(db_name, table.name.as_str()).as_key(),
+            &bytes,
+        );
+        batch.put(
+            SystemKeys::table_version_key(db_name, table.name.as_str(), table.version).as_key(),
+            &bytes,
+        );
+        self.system_db()?.rocks_db()?.write(batch)?;
+        self.forget_table_versions(db_name, Some(table.name.as_str()))
+    }
+
+    fn list_table_versions(
+        &self,
+        db_name: &str,
+        table_name: &str,
+    ) -> Result<Vec<TableSettings>, ConstDBError> {
+        let prefix = SystemKeys::table_version_prefix(db_name, table_name).as_key();
+        let mut read_opts = ReadOptions::default();
+        build_upper_bound(prefix.as_bytes())
+            .into_iter()
+            .for_each(|upper_key| read_opts.set_iterate_upper_bound(upper_key));
+        let iter_mode = IteratorMode::From(prefix.as_ref(), Direction::Forward);
+        let mut versions = Vec::new();
+        for result_kv in self
+            .system_db()?
+            .rocks_db()?
+            .iterator_opt(iter_mode, read_opts)
+        {
+            let (_k, v) = result_kv?;
+            versions.push(TableSettings::parse_from_bytes(v.as_ref())?);
+        }
+        Ok(versions)
+    }
+
+    /// the schema of the table, along with all of its versions
+    fn schema(&self, db_name: &str, table_name: &str) -> Result<SchemaHelper, ConstDBError> {
+        let table = self.get_table(db_name, table_name)?;
+        let history = self.table_versions(db_name, table_name)?;
+        Ok(SchemaHelper::new(table).with_history(history))
+    }
+
+    /// the versions of the table, read from the system db the first time only
+    fn table_versions(
+        &self,
+        db_name: &str,
+        table_name: &str,
+    ) -> Result<Arc<Vec<TableSettings>>, ConstDBError> {
+        let key = (db_name.to_owned(), table_name.to_owned());
+        if let Some(versions) = self.lock_table_versions()?.get(&key) {
+            return Ok(Arc::clone(versions));
+        }
+        let versions = Arc::new(self.list_table_versions(db_name, table_name)?);
+        self.lock_table_versions()?
+            .insert(key, Arc::clone(&versions));
+        Ok(versions)
+    }
+
+    /// forget the versions of the table, or of all the tables of the db if `table_name` is `None`
+    fn forget_table_versions(
+        &self,
+        db_name: &str,
+        table_name: Option<&str>,
+    ) -> Result<(), ConstDBError> {
+        self.lock_table_versions()?
+            .retain(|(db, table), _| match table_name {
+                Some(table_name) => db != db_name || table != table_name,
+                None => db != db_name,
+            });
+        Ok(())
+    }
+
+    fn lock_table_versions(&self) -> Result<MutexGuard<'_, TableVersions>, ConstDBError> {
+        self.table_versions
+            .lock()
+            .map_err(|_| ConstDBError::InvalidStates("table versions lock poisoned".to_owned()))
+    }
+
     /// get the system db
     fn system_db(&self) -> Result<&DBInstance, ConstDBError> {
         self.dbs
@@ -90,11 +349,65 @@ impl Engine {
         Ok(db)
     }
 
+    /// create a database from a checkpoint of the database, which hard links the files of the
+    /// database, along with the settings of its tables
+    pub fn clone_db(&mut self, db_name: &str, name: &str) -> Result<DBSettings, ConstDBError> {
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+        if self.db_exists(name) {
+            return Err(ConstDBError::AlreadyExists(Id::Database(name.to_owned())));
+        }
+        if db_name == "system" || name.is_empty() {
+            return Err(ConstDBError::InvalidArguments(format!(
+                "cannot clone db [{}] to [{}]",
+                db_name, name
+            )));
+        }
+        let path = Path::new(self.settings.root.as_str()).join(name);
+        let rocks_db_path = DBInstance::rocks_db_path(&path);
+        // the files of a dropped db with the same name are left behind
+        if utils::fs::exists(&rocks_db_path)? {
+            std::fs::remove_dir_all(&rocks_db_path)?;
+        }
+        std::fs::create_dir_all(&path)?;
+        // the databases without any table have no rocks db yet
+        if let Some(rocks_db) = db.rocks_db.as_deref() {
+            Checkpoint::new(rocks_db)?.create_checkpoint(&rocks_db_path)?;
+        }
+
+        // the keys of the db in the system db are the kind of the key, the name of the db and the
+        // rest of the key
+        let system_db = self.system_db()?.rocks_db()?;
+        let mut batch = WriteBatch::default();
+        for (k, v) in Self::db_metadata(system_db, db_name)? {
+            let mut key = k[..1].to_vec();
+            key.extend_from_slice(name.as_bytes());
+            key.extend_from_slice(&k[1 + db_name.len()..]);
+            batch.put(key, v);
+        }
+        let mut db_settings = DBSettings::new();
+        db_settings.name = name.to_owned();
+        batch.put(
+            SystemKeys::db_meta_key(name).as_key(),
+            db_settings.write_to_bytes()?,
+        );
+        system_db.write(batch)?;
+        let db = self.open(name)?;
+        self.dbs.insert(name.to_owned(), db);
+        Ok(db_settings)
+    }
+
+    /// drop the db, which fails while any snapshot of it is open
     pub fn drop_db(&mut self, name: &str) -> Result<(), ConstDBError> {
+        if let Some(db) = self.dbs.get(name) {
+            db.check_no_snapshots()?;
+        }
         match self.dbs.remove(name) {
             Some(db) => {
                 DB::destroy(&Options::default(), db.root.as_str())?;
-                Ok(())
+                self.forget_table_versions(name, None)
             }
             None => Err(ConstDBError::NotFound(Id::Database(name.to_owned()))),
         }
@@ -127,7 +440,7 @@ impl Engine {
         let prefix_key = SystemKeys::table_meta_prefix(db_name);
         let prefix = prefix_key.as_key();
         let mut read_opts = ReadOptions::default();
-        Self::build_upper_bound(prefix.as_bytes())
+        build_upper_bound(prefix.as_bytes())
             .into_iter()
             .for_each(|upper_key| read_opts.set_iterate_upper_bound(upper_key));
         let iter_mode = rocksdb::IteratorMode::From(prefix.as_ref(), Direction::Forward);
@@ -169,14 +482,53 @@ impl Engine {
                 input.name.as_str(),
             )));
         }
+        let table = SchemaHelper::init_settings(input)?;
         let db = self.dbs.get_mut(db_name).unwrap();
-        db.create_table(input)?;
-        let system_db = self.system_db()?;
-        system_db.rocks_db()?.put(
-            SystemKeys::table_meta_key(db_name, input.name.as_str()).as_key(),
-            input.write_to_bytes()?,
-        )?;
-        Ok(())
+        db.check_exclusive()?;
+        db.create_table(&table)?;
+        for index in &table.indexes {
+            db.create_index(table.name.as_str(), index.name.as_str())?;
+        }
+        if table.row_history.enabled {
+            db.create_row_history(table.name.as_str())?;
+        }
+        if table.change_feed {
+            db.create_change_log()?;
+        }
+        self.save_table_settings(db_name, &table)
+    }
+
+    pub fn alter_table(
+        &mut self,
+        db_name: &str,
+        table_name: &str,
+        alteration: serde_json::Value,
+    ) -> Result<TableSettings, ConstDBError> {
+        let current = self.get_table(db_name, table_name)?;
+        let schema = SchemaHelper::new(current.clone());
+        let table = schema.alter(&schema.parse_alteration(alteration)?)?;
+        let db = self.dbs.get_mut(db_name).unwrap();
+        if current.row_history.enabled != table.row_history.enabled
+            || (table.change_feed && db.rocks_db_for_changes()?.is_none())
+        {
+            db.check_exclusive()?;
+        }
+        // the row history is never used without its column family, the column family is created
+        // before the history is enabled, and dropped after it is disabled.
+        if !current.row_history.enabled && table.row_history.enabled {
+            let db = self.dbs.get_mut(db_name).unwrap();
+            db.create_row_history(table_name)?;
+        }
+        if table.change_feed {
+            let db = self.dbs.get_mut(db_name).unwrap();
+            db.create_change_log()?;
+        }
+        self.save_table_settings(db_name, &table)?;
+        if current.row_history.enabled && !table.row_history.enabled {
+            let db = self.dbs.get_mut(db_name).unwrap();
+            db.drop_row_history(table_name)?;
+        }
+        Ok(table)
     }
 
     pub fn delete_table(&mut self, db_name: &str, table_name: &str) -> Result<(), ConstDBError> {
@@ -188,124 +540,1184 @@ impl Engine {
             return Err(ConstDBError::NotFound(Id::table(db_name, table_name)));
         }
 
+        let table = self.get_table(db_name, table_name)?;
+        let versions = self.list_table_versions(db_name, table_name)?;
         let db = self.dbs.get_mut(db_name).unwrap();
+        db.check_exclusive()?;
+        for index in &table.indexes {
+            db.drop_index(table_name, index.name.as_str())?;
+        }
+        if table.row_history.enabled {
+            db.drop_row_history(table_name)?;
+        }
         db.delete_table(table_name)?;
-        let system_db = self.system_db()?;
-        system_db
-            .rocks_db()?
-            .delete(SystemKeys::table_meta_key(db_name, table_name).as_key())?;
+        let mut batch = WriteBatch::default();
+        batch.delete(SystemKeys::table_meta_key(db_name, table_name).as_key());
+        for version in versions {
+            batch.delete(
+                SystemKeys::table_version_key(db_name, table_name, version.version).as_key(),
+            );
+        }
+        self.system_db()?.rocks_db()?.write(batch)?;
+        self.forget_table_versions(db_name, Some(table_name))
+    }
+
+    /// create the index and build it for the existing rows
+    pub fn create_index(
+        &mut self,
+        db_name: &str,
+        table_name: &str,
+        index: &Index,
+    ) -> Result<TableSettings, ConstDBError> {
+        let schema = self.schema(db_name, table_name)?;
+        if schema.index(index.name.as_str()).is_some() {
+            return Err(ConstDBError::AlreadyExists(Id::index(
+                db_name,
+                table_name,
+                index.name.as_str(),
+            )));
+        }
+        let table = schema.add_index(index)?;
+        let db = self.dbs.get_mut(db_name).unwrap();
+        db.check_exclusive()?;
+        db.create_index(table_name, index.name.as_str())?;
+        if let Err(e) = self.backfill_index(db_name, &schema, index) {
+            let db = self.dbs.get_mut(db_name).unwrap();
+            db.drop_index(table_name, index.name.as_str())?;
+            return Err(e);
+        }
+        self.save_table_settings(db_name, &table)?;
+        Ok(table)
+    }
+
+    fn backfill_index(
+        &self,
+        db_name: &str,
+        schema: &SchemaHelper,
+        index: &Index,
+    ) -> Result<(), ConstDBError> {
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+        let table_name = schema.table_settings().name.as_str();
+        let cf = db.rocks_db_for_table(table_name)?;
+        let index_cf = db.rocks_db_for_index(table_name, index.name.as_str())?;
+        let rocks_db = db.rocks_db()?;
+        let mut batch = WriteBatch::default();
+        // the unique entries of the batch not written yet
+        let mut batch_keys = HashSet::new();
+        for result_kv in rocks_db.iterator_cf(cf, IteratorMode::Start) {
+            let (k, v) = result_kv?;
+            let row = schema.read_row(&v)?;
+            if let Some(key) = SchemaHelper::build_index_key(index, &row, &k)? {
+                if index.unique
+                    && (batch_keys.contains(&key) || rocks_db.get_cf(index_cf, &key)?.is_some())
+                {
+                    return Err(ConstDBError::AlreadyExists(Id::IndexEntry {
+                        index: index.name.to_owned(),
+                        values: SchemaHelper::index_values(index, &row)?,
+                    }));
+                }
+                batch.put_cf(index_cf, &key, &k);
+                if index.unique {
+                    batch_keys.insert(key);
+                }
+            }
+            if batch.len() >= INDEX_BACKFILL_BATCH_SIZE {
+                rocks_db.write(std::mem::take(&mut batch))?;
+                batch_keys.clear();
+            }
+        }
+        rocks_db.write(batch)?;
         Ok(())
     }
 
+    pub fn drop_index(
+        &mut self,
+        db_name: &str,
+        table_name: &str,
+        index_name: &str,
+    ) -> Result<TableSettings, ConstDBError> {
+        let mut table = self.get_table(db_name, table_name)?;
+        let pos = table
+            .indexes
+            .iter()
+            .position(|i| i.name == index_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::index(db_name, table_name, index_name)))?;
+        self.dbs.get_mut(db_name).unwrap().check_exclusive()?;
+        table.indexes.remove(pos);
+        table.version += 1;
+        // the index is removed from the settings first, so that it is never used without its
+        // column family
+        self.save_table_settings(db_name, &table)?;
+        let db = self.dbs.get_mut(db_name).unwrap();
+        db.drop_index(table_name, index_name)?;
+        Ok(table)
+    }
+
+    /// query the rows by the primary key, returns the row along with its entity tag if the primary
+    /// key is complete, otherwise a page of the rows
     pub fn query_by_key(
         &self,
         db_name: &str,
         table_name: &str,
         params: HashMap<String, String>,
-    ) -> Result<String, ConstDBError> {
-        let table = self.get_table(db_name, table_name)?;
-        let schema = SchemaHelper::new(table);
+    ) -> Result<(String, Option<String>), ConstDBError> {
+        if let Some(index_name) = params.get("index") {
+            let page = self.query_by_index(db_name, table_name, index_name, &params)?;
+            return Ok((page, None));
+        }
+        let schema = self.schema(db_name, table_name)?;
         let pk = schema.build_pk_from_params(&params)?;
         let db = self
             .dbs
             .get(db_name)
             .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+        if let Some(as_of) = params.get("as_of") {
+            let as_of = Self::parse_as_of(&schema, as_of)?;
+            let snapshot = db.snapshot(params.get("snapshot").map(|s| s.as_str()))?;
+            return match Self::row_as_of(db, &schema, pk.complete()?, as_of, snapshot.as_deref())? {
+                Some(v) => Ok((schema.row_to_json(&v)?, None)),
+                None => Err(ConstDBError::NotFound(Id::Data)),
+            };
+        }
 
-        match pk {
+        let (lower, upper) = match pk {
+            PrimaryKey::Prefix(prefix) if prefix.is_empty() => {
+                return Err(ConstDBError::InvalidArguments(
+                    "primary key is missing, use scan=all to scan the whole table".to_owned(),
+                ))
+            }
             PrimaryKey::Prefix(prefix) => {
-                let iter_mode = rocksdb::IteratorMode::From(prefix.as_slice(), Direction::Forward);
-                let mut read_opts = ReadOptions::default();
-                Self::build_upper_bound(&prefix)
-                    .into_iter()
-                    .for_each(|upper_key| read_opts.set_iterate_upper_bound(upper_key));
-                let table = db.rocks_db_for_table(table_name)?;
-                let rows_iter = db.rocks_db()?.iterator_cf_opt(table, read_opts, iter_mode);
-                let mut rows = Vec::new();
-                for result_kv in rows_iter {
-                    let (_k, v) = result_kv?;
-                    rows.push(String::from_utf8(v.into()).unwrap());
-                }
-                Ok(format!("[{}]", rows.join(",")))
+                let upper = build_upper_bound(&prefix);
+                (prefix, upper)
             }
+            PrimaryKey::Range { lower, upper } => (lower, upper),
             PrimaryKey::Complete(key) => {
                 let table = db.rocks_db_for_table(table_name)?;
-                let opt_value = db.rocks_db()?.get_cf(table, key)?;
-                match opt_value {
-                    Some(v) => Ok(String::from_utf8(v).unwrap()),
+                let snapshot = db.snapshot(params.get("snapshot").map(|s| s.as_str()))?;
+                let read_opts = DBInstance::read_options(snapshot.as_deref());
+                let opt_value = db.rocks_db()?.get_cf_opt(table, key, &read_opts)?;
+                return match opt_value {
+                    Some(v) => Ok((
+                        schema.row_to_json(&v)?,
+                        Some(Precondition::entity_tag(SchemaHelper::revision(&v)?)),
+                    )),
                     None => Err(ConstDBError::NotFound(Id::Data)),
-                }
+                };
             }
+        };
+        let options = ScanOptions::from_params(&params, self.settings.max_page_size)?;
+        let mut rows = Vec::new();
+        let table = db.rocks_db_for_table(table_name)?;
+        let snapshot = db.snapshot(options.snapshot.as_deref())?;
+        let next_cursor = Self::scan_rows(
+            db,
+            table,
+            lower,
+            upper,
+            options,
+            snapshot.as_deref(),
+            |value| {
+                rows.push(schema.row_to_json(value)?);
+                Ok(())
+            },
+        )?;
+        Ok((Page { rows, next_cursor }.to_json()?, None))
+    }
+
+    /// the time to read the row as of, in milliseconds since the epoch, which should be within the
+    /// time the row history is kept
+    fn parse_as_of(schema: &SchemaHelper, as_of: &str) -> Result<u64, ConstDBError> {
+        let table_name = schema.table_settings().name.as_str();
+        let row_history = schema.row_history().ok_or_else(|| {
+            ConstDBError::InvalidArguments(format!(
+                "row history of table [{}] is not enabled",
+                table_name
+            ))
+        })?;
+        let as_of = as_of.parse::<u64>().map_err(|_| {
+            ConstDBError::InvalidArguments(format!(
+                "invalid as_of: {}, should be milliseconds since the epoch",
+                as_of
+            ))
+        })?;
+        let kept_from = match row_history.retention_secs {
+            0 => row_history.enabled_at,
+            secs => row_history
+                .enabled_at
+                .max(now_millis().saturating_sub(secs.saturating_mul(1000))),
+        };
+        match as_of >= kept_from {
+            true => Ok(as_of),
+            false => Err(ConstDBError::InvalidArguments(format!(
+                "as_of {} is before the row history of table [{}] is kept from {}",
+                as_of, table_name, kept_from
+            ))),
         }
     }
 
-    fn build_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
-        for pos in (0..prefix.len()).rev() {
-            let v = &prefix[pos];
-            if *v != 0xFF {
-                let mut stop_key = prefix.to_owned();
-                stop_key[pos] = v + 1;
-                return Some(stop_key);
+    /// the stored row as of the time, which is the version replaced first after the time, or the
+    /// current row if it has not been replaced since
+    fn row_as_of(
+        db: &DBInstance,
+        schema: &SchemaHelper,
+        pk: &[u8],
+        as_of: u64,
+        snapshot: Option<&DBSnapshot>,
+    ) -> Result<Option<Vec<u8>>, ConstDBError> {
+        let table_name = schema.table_settings().name.as_str();
+        let rocks_db = db.rocks_db()?;
+        let history = db.rocks_db_for_row_history(table_name)?;
+        let lower = version_key(pk, as_of.saturating_add(1));
+        let mut read_opts = DBInstance::read_options(snapshot);
+        read_opts.set_iterate_lower_bound(lower.as_slice());
+        read_opts.set_iterate_upper_bound(version_key(pk, u64::MAX));
+        let iter_mode = IteratorMode::From(lower.as_slice(), Direction::Forward);
+        for result_kv in rocks_db.iterator_cf_opt(history, read_opts, iter_mode) {
+            let (k, v) = result_kv?;
+            if Self::version_of(&k, pk)?.is_some() {
+                return Ok(match v.is_empty() {
+                    true => None,
+                    false => Some(v.to_vec()),
+                });
             }
         }
-        None
+        let table = db.rocks_db_for_table(table_name)?;
+        let read_opts = DBInstance::read_options(snapshot);
+        Ok(rocks_db.get_cf_opt(table, pk, &read_opts)?)
     }
 
-    pub fn insert(&self, db_name: &str, table_name: &str, data: Bytes) -> Result<(), ConstDBError> {
-        let table = self.get_table(db_name, table_name)?;
-        let schema = SchemaHelper::new(table);
-        let primary_key = schema.build_pk_from_json(&data)?;
+    /// the time the version of the key was replaced at, `None` if the version is of another row
+    fn version_of(key: &[u8], pk: &[u8]) -> Result<Option<u64>, ConstDBError> {
+        match decode_version_key(key) {
+            Some((key_pk, replaced_at)) => Ok((key_pk == pk).then_some(replaced_at)),
+            None => Err(ConstDBError::InvalidStates(
+                "invalid key of row history".to_owned(),
+            )),
+        }
+    }
+
+    /// all the kept versions of the row of the complete primary key, in the order of time
+    pub fn row_history(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        params: HashMap<String, String>,
+    ) -> Result<String, ConstDBError> {
+        let schema = self.schema(db_name, table_name)?;
+        if schema.row_history().is_none() {
+            return Err(ConstDBError::InvalidArguments(format!(
+                "row history of table [{}] is not enabled",
+                table_name
+            )));
+        }
+        let pk = schema.build_pk_from_params(&params)?;
+        let pk = pk.complete()?;
         let db = self
             .dbs
             .get(db_name)
             .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
-
+        let rocks_db = db.rocks_db()?;
+        let history = db.rocks_db_for_row_history(table_name)?;
+        let snapshot = db.snapshot(params.get("snapshot").map(|s| s.as_str()))?;
+        let lower = version_key(pk, 0);
+        let mut read_opts = DBInstance::read_options(snapshot.as_deref());
+        read_opts.set_iterate_lower_bound(lower.as_slice());
+        read_opts.set_iterate_upper_bound(version_key(pk, u64::MAX));
+        let iter_mode = IteratorMode::From(lower.as_slice(), Direction::Forward);
+        let mut versions = Vec::new();
+        // the time the row is written before the first kept version is unknown
+        let mut valid_from = None;
+        for result_kv in rocks_db.iterator_cf_opt(history, read_opts, iter_mode) {
+            let (k, v) = result_kv?;
+            let replaced_at = match Self::version_of(&k, pk)? {
+                Some(replaced_at) => replaced_at,
+                None => continue,
+            };
+            // an empty version is the row not existing
+            if !v.is_empty() {
+                versions.push(RowVersion {
+                    valid_from,
+                    valid_to: Some(replaced_at),
+                    row: schema.read_row(&v)?,
+                });
+            }
+            valid_from = Some(replaced_at);
+        }
         let table = db.rocks_db_for_table(table_name)?;
-        db.rocks_db()?
-            .put_cf(table, primary_key.complete()?, &data)?;
+        let read_opts = DBInstance::read_options(snapshot.as_deref());
+        if let Some(v) = rocks_db.get_cf_opt(table, pk, &read_opts)? {
+            versions.push(RowVersion {
+                valid_from,
+                valid_to: None,
+                row: schema.read_row(&v)?,
+            });
+        }
+        if versions.is_empty() {
+            return Err(ConstDBError::NotFound(Id::Data));
+        }
+        Ok(serde_json::json!({ "versions": versions }).to_string())
+    }
+
+    /// delete the versions of the rows replaced before the retention of the row history of the
+    /// tables
+    pub fn prune_row_history(&self) -> Result<(), ConstDBError> {
+        let now = now_millis();
+        for (db_name, db) in &self.dbs {
+            for table in self.list_table(db_name)? {
+                let retention_secs = table.row_history.retention_secs;
+                if !table.row_history.enabled || retention_secs == 0 {
+                    continue;
+                }
+                let cutoff = now.saturating_sub(retention_secs.saturating_mul(1000));
+                let rocks_db = db.rocks_db()?;
+                let history = db.rocks_db_for_row_history(table.name.as_str())?;
+                let mut batch = WriteBatch::default();
+                let mut count = 0;
+                for result_kv in rocks_db.iterator_cf(history, IteratorMode::Start) {
+                    let (k, _) = result_kv?;
+                    match decode_version_key(&k) {
+                        Some((_, replaced_at)) if replaced_at < cutoff => {
+                            batch.delete_cf(history, &k);
+                            count += 1;
+                        }
+                        _ => {}
+                    }
+                    if batch.len() >= ROW_HISTORY_PRUNE_BATCH_SIZE {
+                        rocks_db.write(std::mem::take(&mut batch))?;
+                    }
+                }
+                rocks_db.write(batch)?;
+                if count > 0 {
+                    println!(
+                        "pruned {} versions of rows of table [{}.{}]",
+                        count, db_name, table.name
+                    );
+                }
+            }
+        }
         Ok(())
     }
 
-    pub fn upsert(&self, db_name: &str, table_name: &str, data: Bytes) -> Result<(), ConstDBError> {
-        let table = self.get_table(db_name, table_name)?;
-        let schema = SchemaHelper::new(table);
-        let primary_key = schema.build_pk_from_json(&data)?;
-        // let primary_key = schema.build_pk_from_params(&params)?;
+    /// check the change feed of the database, or of the table if given, returns the sequence number
+    /// to read the changes after, which is the last change if `since` is not given, along with
+    /// the receiver of the sequence number of the last change.
+    pub fn subscribe_changes(
+        &self,
+        db_name: &str,
+        table_name: Option<&str>,
+        since: Option<&str>,
+    ) -> Result<(u64, watch::Receiver<u64>), ConstDBError> {
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+        if let Some(table_name) = table_name {
+            if !self.get_table(db_name, table_name)?.change_feed {
+                return Err(ConstDBError::InvalidArguments(format!(
+                    "change feed of table [{}] is not enabled",
+                    table_name
+                )));
+            }
+        }
+        // subscribed first, so that no change is missed after the last one read
+        let receiver = db.changes.subscribe();
+        let since = match since {
+            Some(since) => since.parse::<u64>().map_err(|_| {
+                ConstDBError::InvalidArguments(format!(
+                    "invalid since: {}, should be a sequence number",
+                    since
+                ))
+            })?,
+            None => return Ok((db.changes.last_seq()?, receiver)),
+        };
+        db.changes.check_since(since)?;
+        Ok((since, receiver))
+    }
+
+    /// read at most `limit` changes after the sequence number, of the table if given. returns
+    /// the changes along with the sequence number to read the next changes after.
+    pub fn read_changes(
+        &self,
+        db_name: &str,
+        table_name: Option<&str>,
+        since: u64,
+        limit: usize,
+    ) -> Result<(Vec<Change>, u64), ConstDBError> {
         let db = self
             .dbs
             .get(db_name)
             .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+        let cf = match db.rocks_db_for_changes()? {
+            Some(cf) => cf,
+            None => return Ok((Vec::new(), since)),
+        };
+        // the changes after the last sequence number committed may be followed by the ones of the
+        // commits still in flight
+        let committed = db.changes.last_seq()?;
+        if since >= committed {
+            db.changes.check_since(since)?;
+            return Ok((Vec::new(), since));
+        }
+        let lower = since.saturating_add(1).to_be_bytes();
+        let mut read_opts = ReadOptions::default();
+        read_opts.set_iterate_lower_bound(lower);
+        read_opts.set_iterate_upper_bound(committed.saturating_add(1).to_be_bytes());
+        let iter_mode = IteratorMode::From(&lower, Direction::Forward);
+        let mut changes = Vec::new();
+        let mut last_seq = since;
+        // the changes of other tables are skipped, but count to the limit as well
+        for result_kv in db
+            .rocks_db()?
+            .iterator_cf_opt(cf, read_opts, iter_mode)
+            .take(limit)
+        {
+            let (k, v) = result_kv?;
+            last_seq = decode_change_key(&k)?;
+            let change: Change = serde_json::from_slice(&v)?;
+            let of_table = match table_name {
+                Some(table_name) => change.table == table_name,
+                None => true,
+            };
+            if of_table {
+                changes.push(change);
+            }
+        }
+        // checked after reading, as the changes are marked as pruned before they are deleted
+        db.changes.check_since(since)?;
+        Ok((changes, last_seq))
+    }
 
-        let pk = primary_key.complete()?;
+    /// watch the rows of the primary key params after the `since` revision, which is a sequence
+    /// number of the change log. returns the watch along with the receiver of the sequence number
+    /// of the last change.
+    pub fn watch_rows(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        params: &HashMap<String, String>,
+    ) -> Result<(RowWatch, watch::Receiver<u64>), ConstDBError> {
+        let schema = self.schema(db_name, table_name)?;
+        let pk = schema.build_pk_from_params(params)?;
+        let timeout = match params.get("timeout") {
+            Some(timeout) => match timeout.parse::<u64>() {
+                Ok(timeout) if timeout > 0 && timeout <= MAX_WATCH_TIMEOUT => timeout,
+                _ => {
+                    return Err(ConstDBError::InvalidArguments(format!(
+                        "invalid timeout: {}, should be within 1 to {} seconds",
+                        timeout, MAX_WATCH_TIMEOUT
+                    )))
+                }
+            },
+            None => DEFAULT_WATCH_TIMEOUT,
+        };
+        let since = params.get("since").map(|s| s.as_str());
+        let (since, receiver) = self.subscribe_changes(db_name, Some(table_name), since)?;
+        let watch = RowWatch {
+            pk,
+            since,
+            timeout: Duration::from_secs(timeout),
+        };
+        Ok((watch, receiver))
+    }
+
+    /// the changes of the watched rows after the revision of the watch as
+    /// `{"revision": ..., "changes": [...]}`, or `None` if the rows are not changed yet. the
+    /// revision of the watch moves past the changes read.
+    pub fn poll_watch(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        watch: &mut RowWatch,
+    ) -> Result<Option<String>, ConstDBError> {
+        let schema = self.schema(db_name, table_name)?;
+        loop {
+            let (changes, last_seq) = self.read_changes(
+                db_name,
+                Some(table_name),
+                watch.since,
+                self.settings.max_page_size,
+            )?;
+            if last_seq == watch.since {
+                return Ok(None);
+            }
+            watch.since = last_seq;
+            let mut watched = Vec::new();
+            for change in changes {
+                if watch
+                    .pk
+                    .matches(schema.build_pk_from_row(&change.key)?.complete()?)
+                {
+                    watched.push(change);
+                }
+            }
+            if !watched.is_empty() {
+                let result = serde_json::json!({ "revision": last_seq, "changes": watched });
+                return Ok(Some(result.to_string()));
+            }
+        }
+    }
+
+    /// delete the changes made before the retention of the change logs, the last change of a log
+    /// is always kept to continue the sequence numbers
+    pub fn prune_changes(&self) -> Result<(), ConstDBError> {
+        let cutoff =
+            now_millis().saturating_sub(self.settings.change_retention_secs.saturating_mul(1000));
+        for (db_name, db) in &self.dbs {
+            let cf = match db.rocks_db_for_changes()? {
+                Some(cf) => cf,
+                None => continue,
+            };
+            let rocks_db = db.rocks_db()?;
+            let mut batch = WriteBatch::default();
+            let mut count = 0;
+            let mut pruned = 0;
+            let mut iter = rocks_db.iterator_cf(cf, IteratorMode::Start).peekable();
+            while let Some(result_kv) = iter.next() {
+                let (k, v) = result_kv?;
+                // the changes are in the order of time
+                let change: Change = serde_json::from_slice(&v)?;
+                if change.ts >= cutoff || iter.peek().is_none() {
+                    break;
+                }
+                batch.delete_cf(cf, &k);
+                pruned = decode_change_key(&k)?;
+                count += 1;
+                if batch.len() >= CHANGE_LOG_PRUNE_BATCH_SIZE {
+                    db.changes.prune(pruned)?;
+                    rocks_db.write(std::mem::take(&mut batch))?;
+                }
+            }
+            db.changes.prune(pruned)?;
+            rocks_db.write(batch)?;
+            if count > 0 {
+                println!("pruned {} changes of db [{}]", count, db_name);
+            }
+        }
+        Ok(())
+    }
+
+    /// page through the rows by the values of the index, in the order of the index
+    fn query_by_index(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        index_name: &str,
+        params: &HashMap<String, String>,
+    ) -> Result<String, ConstDBError> {
+        let schema = self.schema(db_name, table_name)?;
+        let index = schema
+            .index(index_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::index(db_name, table_name, index_name)))?;
+        let (lower, upper) = match SchemaHelper::build_index_key_from_params(index, params)? {
+            PrimaryKey::Range { lower, upper } => (lower, upper),
+            key => {
+                let prefix = key.bytes().to_vec();
+                let upper = build_upper_bound(&prefix);
+                (prefix, upper)
+            }
+        };
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+        let options = ScanOptions::from_params(params, self.settings.max_page_size)?;
         let table = db.rocks_db_for_table(table_name)?;
+        let index_cf = db.rocks_db_for_index(table_name, index_name)?;
         let rocks_db = db.rocks_db()?;
-        let opt_existing = rocks_db.get_cf(table, pk)?;
-        let upsert = match opt_existing {
-            Some(existing) => schema.update(&existing, &data)?,
-            None => data,
+        // the rows are read at the same snapshot as the index
+        let snapshot = db.snapshot(options.snapshot.as_deref())?;
+        let read_opts = DBInstance::read_options(snapshot.as_deref());
+        let mut rows = Vec::new();
+        let next_cursor = Self::scan_rows(
+            db,
+            index_cf,
+            lower,
+            upper,
+            options,
+            snapshot.as_deref(),
+            |pk| {
+                if let Some(v) = rocks_db.get_cf_opt(table, pk, &read_opts)? {
+                    rows.push(schema.row_to_json(&v)?);
+                }
+                Ok(())
+            },
+        )?;
+        Page { rows, next_cursor }.to_json()
+    }
+
+    /// validate the full table scan and parse its options
+    pub fn full_scan_options(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        params: &HashMap<String, String>,
+    ) -> Result<ScanOptions, ConstDBError> {
+        self.get_table(db_name, table_name)?;
+        ScanOptions::from_params(params, self.settings.max_scan_size)
+    }
+
+    /// hold a snapshot of the database for a scan across pages, at the snapshot of the token if
+    /// given
+    pub fn hold_snapshot(
+        &self,
+        db_name: &str,
+        token: Option<&str>,
+    ) -> Result<HeldSnapshot, ConstDBError> {
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+        db.hold_snapshot(token)
+    }
+
+    /// keep the snapshot of the token for another ttl
+    pub fn keep_snapshot(&self, db_name: &str, token: &str) -> Result<(), ConstDBError> {
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+        db.snapshot(Some(token))?;
+        Ok(())
+    }
+
+    /// stream the rows of the whole table at the held snapshot to `on_row`, returns the cursor to
+    /// resume the scan
+    pub fn full_scan<F>(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        options: ScanOptions,
+        snapshot: &HeldSnapshot,
+        mut on_row: F,
+    ) -> Result<Option<String>, ConstDBError>
+    where
+        F: FnMut(String) -> Result<(), ConstDBError>,
+    {
+        let schema = self.schema(db_name, table_name)?;
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+        let table = db.rocks_db_for_table(table_name)?;
+        let snapshot = db.held_snapshot(snapshot)?;
+        Self::scan_rows(
+            db,
+            table,
+            Vec::new(),
+            None,
+            options,
+            Some(&snapshot),
+            |value| on_row(schema.row_to_json(value)?),
+        )
+    }
+
+    /// scan the column family with keys within `[lower, upper)` and pass the stored values to
+    /// `on_row`, returns the cursor of the next page if the scan stopped at the limit.
+    fn scan_rows<F>(
+        db: &DBInstance,
+        cf: &ColumnFamily,
+        lower: Vec<u8>,
+        upper: Option<Vec<u8>>,
+        options: ScanOptions,
+        snapshot: Option<&DBSnapshot>,
+        mut on_row: F,
+    ) -> Result<Option<String>, ConstDBError>
+    where
+        F: FnMut(&[u8]) -> Result<(), ConstDBError>,
+    {
+        // resume right after the cursor, `cursor + 0x00` is the smallest key greater than it
+        let (lower, upper) = match (options.cursor, options.direction) {
+            (Some(mut cursor), Direction::Forward) => {
+                cursor.push(0x00);
+                (lower.max(cursor), upper)
+            }
+            (Some(cursor), Direction::Reverse) => match upper {
+                Some(upper) => (lower, Some(upper.min(cursor))),
+                None => (lower, Some(cursor)),
+            },
+            (None, _) => (lower, upper),
+        };
+        // the reverse scan starts from the last key before the upper bound
+        let iter_mode = match options.direction {
+            Direction::Forward => IteratorMode::From(lower.as_slice(), Direction::Forward),
+            Direction::Reverse => IteratorMode::End,
+        };
+        let mut read_opts = DBInstance::read_options(snapshot);
+        read_opts.set_iterate_lower_bound(lower.as_slice());
+        upper
+            .into_iter()
+            .for_each(|upper_key| read_opts.set_iterate_upper_bound(upper_key));
+        let mut rows_iter = db.rocks_db()?.iterator_cf_opt(cf, read_opts, iter_mode);
+        let mut last_key = None;
+        for result_kv in rows_iter.by_ref().take(options.limit) {
+            let (k, v) = result_kv?;
+            on_row(&v)?;
+            last_key = Some(k);
+        }
+        match rows_iter.next() {
+            Some(result_kv) => {
+                result_kv?;
+                Ok(last_key.map(|k| encode_cursor(&k)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// take a snapshot of the database to read at across requests, the snapshot is released once
+    /// it is not used for `ttl` seconds. returns the token of the snapshot.
+    pub fn create_snapshot(
+        &self,
+        db_name: &str,
+        params: &HashMap<String, String>,
+    ) -> Result<(String, u64), ConstDBError> {
+        let ttl = match params.get("ttl") {
+            Some(ttl) => match ttl.parse::<u64>() {
+                Ok(ttl) if ttl > 0 && ttl <= self.settings.max_snapshot_ttl => ttl,
+                _ => {
+                    return Err(ConstDBError::InvalidArguments(format!(
+                        "invalid ttl: {}, should be within 1 to {} seconds",
+                        ttl, self.settings.max_snapshot_ttl
+                    )))
+                }
+            },
+            None => DEFAULT_SNAPSHOT_TTL.min(self.settings.max_snapshot_ttl),
         };
-        rocks_db.put_cf(table, pk, upsert)?;
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+        let token = db.create_snapshot(Duration::from_secs(ttl))?;
+        Ok((token, ttl))
+    }
+
+    pub fn release_snapshot(&self, db_name: &str, token: &str) -> Result<(), ConstDBError> {
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+        db.snapshots.release(token)
+    }
+
+    /// release the snapshots of all the databases that are not used for their ttl
+    pub fn reap_snapshots(&self) -> Result<(), ConstDBError> {
+        for (db_name, db) in &self.dbs {
+            let count = db.snapshots.reap()?;
+            if count > 0 {
+                println!("released {} idle snapshots of db [{}]", count, db_name);
+            }
+        }
         Ok(())
     }
 
+    pub fn insert(&self, db_name: &str, table_name: &str, data: Bytes) -> Result<(), ConstDBError> {
+        let schema = self.schema(db_name, table_name)?;
+        let row = SchemaHelper::get_json_object(&data)?;
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+
+        // the key is read for update, so that only one of the concurrent inserts of the same key
+        // can commit
+        Self::write_with_retries(db, |writes| {
+            writes.insert(&schema, row.clone())?;
+            Ok(())
+        })
+    }
+
+    /// make the writes in a transaction, which is made again from the start if it conflicts with
+    /// a concurrent write on commit
+    fn write_with_retries<T, F>(db: &DBInstance, mut write: F) -> Result<T, ConstDBError>
+    where
+        F: FnMut(&RowWrites) -> Result<T, ConstDBError>,
+    {
+        let mut retries = 0;
+        loop {
+            let writes = RowWrites::new(db)?;
+            let result = write(&writes).and_then(|value| writes.commit().map(|()| value));
+            match result {
+                Err(ConstDBError::Conflict(_)) if retries < CONFLICT_RETRIES => retries += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// merge the data into the existing row, or replace the existing row as a whole with
+    /// `mode=replace`
+    pub fn upsert(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        params: HashMap<String, String>,
+        precondition: &Precondition,
+        data: Bytes,
+    ) -> Result<(), ConstDBError> {
+        let mode = UpsertMode::from_params(&params)?;
+        let schema = self.schema(db_name, table_name)?;
+        let row = SchemaHelper::get_json_object(&data)?;
+        let primary_key = schema.build_pk_from_row(&row)?;
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+
+        Self::write_with_retries(db, |writes| {
+            writes.check(&schema, primary_key.complete()?, precondition)?;
+            writes.upsert(&schema, row.clone(), mode)?;
+            Ok(())
+        })
+    }
+
+    /// patch the row of the primary key with a json merge patch or a json patch, by the content
+    /// type
+    pub fn patch(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        params: HashMap<String, String>,
+        precondition: &Precondition,
+        content_type: &str,
+        data: Bytes,
+    ) -> Result<(), ConstDBError> {
+        let patch = RowPatch::parse(content_type, &data)?;
+        let schema = self.schema(db_name, table_name)?;
+        let primary_key = schema.build_pk_from_params(&params)?;
+        let pk = primary_key.complete()?;
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+
+        Self::write_with_retries(db, |writes| {
+            writes.check(&schema, pk, precondition)?;
+            writes.patch(&schema, pk, &patch)?;
+            Ok(())
+        })
+    }
+
+    /// update the row of the primary key by the update operators, e.g. `$inc` and `$push`,
+    /// returns the updated row in json
+    pub fn update(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        params: HashMap<String, String>,
+        data: Bytes,
+    ) -> Result<String, ConstDBError> {
+        let update = RowUpdate::parse(&data)?;
+        let schema = self.schema(db_name, table_name)?;
+        update.validate(schema.table_settings())?;
+        let primary_key = schema.build_pk_from_params(&params)?;
+        let pk = primary_key.complete()?;
+        let pk_fields: serde_json::Map<String, serde_json::Value> = schema
+            .decode_pk(pk)?
+            .into_iter()
+            .map(|(k, v)| (k.name, v.to_json()))
+            .collect();
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+
+        let row = Self::write_with_retries(db, |writes| {
+            writes.update(&schema, pk, pk_fields.clone(), &update)
+        })?;
+        Ok(serde_json::to_string(&row)?)
+    }
+
+    /// write the row only if the current row is as expected, a write that conflicts with a
+    /// concurrent one is retried against the new current row. returns whether the row is written
+    /// along with the row after all in json.
+    pub fn compare_and_swap(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        data: Bytes,
+    ) -> Result<String, ConstDBError> {
+        let cas = CompareAndSwap::parse(&data)?;
+        let schema = self.schema(db_name, table_name)?;
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+
+        let (swapped, row) =
+            Self::write_with_retries(db, |writes| writes.compare_and_swap(&schema, &cas))?;
+        Ok(serde_json::json!({ "swapped": swapped, "row": row }).to_string())
+    }
+
     pub fn delete(
         &self,
         db_name: &str,
         table_name: &str,
         params: HashMap<String, String>,
+        precondition: &Precondition,
     ) -> Result<(), ConstDBError> {
-        let table = self.get_table(db_name, table_name)?;
-        let schema = SchemaHelper::new(table);
+        let schema = self.schema(db_name, table_name)?;
         let primary_key = schema.build_pk_from_params(&params)?;
+        let pk = primary_key.complete()?;
         let db = self
             .dbs
             .get(db_name)
             .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
 
-        let table = db.rocks_db_for_table(table_name)?;
-        db.rocks_db()?.delete_cf(table, primary_key.complete()?)?;
+        Self::write_with_retries(db, |writes| {
+            writes.check(&schema, pk, precondition)?;
+            writes.delete(&schema, pk)?;
+            Ok(())
+        })
+    }
+
+    /// apply the insert/upsert/replace/delete operations on the table atomically, the whole batch
+    /// is rejected if any of the operations fails. returns the results of the operations in json.
+    pub fn write_batch(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        data: Bytes,
+    ) -> Result<String, ConstDBError> {
+        self.apply_operations(db_name, Some(table_name), &data)
+    }
+
+    /// apply the operations on the tables of the database in one transaction
+    pub fn transaction(&self, db_name: &str, data: Bytes) -> Result<String, ConstDBError> {
+        self.apply_operations(db_name, None, &data)
+    }
+
+    /// apply the operations in one transaction, the operations are on the given table, or on the
+    /// tables named by the operations if no table is given.
+    fn apply_operations(
+        &self,
+        db_name: &str,
+        table_name: Option<&str>,
+        data: &[u8],
+    ) -> Result<String, ConstDBError> {
+        let operations = Operation::parse_all(data)?;
+        let db = self
+            .dbs
+            .get(db_name)
+            .ok_or_else(|| ConstDBError::NotFound(Id::Database(db_name.to_owned())))?;
+
+        let mut schemas: HashMap<String, SchemaHelper> = HashMap::new();
+        let writes = RowWrites::new(db)?;
+        let mut results = Vec::with_capacity(operations.len());
+        for (i, operation) in operations.into_iter().enumerate() {
+            let result = self
+                .apply_operation(&writes, &mut schemas, db_name, table_name, operation)
+                .map_err(|e| e.of_operation(i))?;
+            results.push(result);
+        }
+        writes.commit()?;
+        Ok(serde_json::json!({ "results": results }).to_string())
+    }
+
+    fn apply_operation(
+        &self,
+        writes: &RowWrites,
+        schemas: &mut HashMap<String, SchemaHelper>,
+        db_name: &str,
+        table_name: Option<&str>,
+        operation: Operation,
+    ) -> Result<WriteResult, ConstDBError> {
+        let table_name = match (table_name, operation.table.as_deref()) {
+            (Some(table_name), None) => table_name.to_owned(),
+            (Some(table_name), Some(op_table)) if table_name == op_table => table_name.to_owned(),
+            (Some(table_name), Some(op_table)) => {
+                return Err(ConstDBError::InvalidArguments(format!(
+                    "operation on table [{}] in the batch of table [{}]",
+                    op_table, table_name
+                )))
+            }
+            (None, Some(op_table)) => op_table.to_owned(),
+            (None, None) => {
+                return Err(ConstDBError::InvalidArguments(
+                    "table of the operation is missing".to_owned(),
+                ))
+            }
+        };
+        if !schemas.contains_key(&table_name) {
+            let schema = self.schema(db_name, table_name.as_str())?;
+            schemas.insert(table_name.to_owned(), schema);
+        }
+        writes.apply(&schemas[&table_name], operation)
+    }
+
+    fn backups(&self) -> Result<&Backups, ConstDBError> {
+        self.backups.as_ref().ok_or_else(|| {
+            ConstDBError::InvalidArguments(
+                "backup dir is not set, start the server with --backup-dir".to_owned(),
+            )
+        })
+    }
+
+    /// back up all the databases along with the system db
+    pub fn create_backup(&self) -> Result<ServerBackup, ConstDBError> {
+        let dbs: Vec<&DBInstance> = self.dbs.values().collect();
+        self.backups()?.create(&dbs)
+    }
+
+    pub fn list_backups(&self) -> Result<Vec<ServerBackup>, ConstDBError> {
+        self.backups()?.list()
+    }
+
+    /// purge the old backups and keep the latest `keep` ones, returns the backups kept
+    pub fn purge_backups(&self, keep: usize) -> Result<Vec<ServerBackup>, ConstDBError> {
+        self.backups()?.purge(keep)
+    }
+
+    /// restore the database from the backup, or the latest backup if not given, along with the
+    /// settings of its tables. the database is closed only while its files are replaced, and is
+    /// re-opened as it was if the restore fails. it fails while any snapshot of the database is
+    /// open.
+    pub fn restore_db(
+        &mut self,
+        backup_id: Option<u32>,
+        db_name: &str,
+    ) -> Result<ServerBackup, ConstDBError> {
+        if db_name == "system" {
+            return Err(ConstDBError::InvalidArguments(
+                "system db is restored along with the whole server by the restore command"
+                    .to_owned(),
+            ));
+        }
+        if let Some(db) = self.dbs.get(db_name) {
+            db.check_no_snapshots()?;
+        }
+        let backups = self.backups()?;
+        let backup = match backup_id {
+            Some(backup_id) => backups.get(backup_id)?,
+            None => backups.latest()?,
+        };
+        let metadata =
+            backups.with_system_db(&backup, |system_db| Self::db_metadata(system_db, db_name))?;
+        if metadata.is_empty() {
+            return Err(ConstDBError::InvalidArguments(format!(
+                "db [{}] is not found in backup {}",
+                db_name, backup.id
+            )));
+        }
+        let path = Path::new(self.settings.root.as_str()).join(db_name);
+        let rocks_db_path = DBInstance::rocks_db_path(&path);
+        let restoring_path = utils::fs::sibling(&rocks_db_path, "restoring");
+        // the db is restored next to its files, which are replaced only once it is restored
+        if backup.dbs.contains_key(db_name) {
+            std::fs::create_dir_all(&path)?;
+            backups.restore_db(&backup, db_name, &restoring_path)?;
+        }
+        let previous_metadata = Self::db_metadata(self.system_db()?.rocks_db()?, db_name)?;
+        // the db is closed before its files are replaced
+        self.dbs.remove(db_name);
+        let replaced = match utils::fs::move_aside(&rocks_db_path) {
+            Ok(replaced) => replaced,
+            Err(e) => {
+                utils::fs::remove_dir(&restoring_path)?;
+                return Err(self.reopen_db(db_name, e.into()));
+            }
+        };
+        let result = self.replace_db(db_name, &restoring_path, &rocks_db_path, metadata);
+        match result {
+            Ok(db) => {
+                self.dbs.insert(db_name.to_owned(), db);
+                if let Some(replaced) = replaced {
+                    utils::fs::remove_dir(replaced)?;
+                }
+                Ok(backup)
+            }
+            Err(e) => {
+                // the files and the settings of the db are put back as they were
+                let rollback = utils::fs::remove_dir(&restoring_path)
+                    .and_then(|_| utils::fs::move_back(&rocks_db_path, replaced))
+                    .map_err(ConstDBError::from)
+                    .and_then(|_| self.replace_db_metadata(db_name, previous_metadata));
+                if let Err(rollback_e) = rollback {
+                    println!(
+                        "failed to roll back the restore of db [{}]: {}",
+                        db_name,
+                        rollback_e.to_string()
+                    );
+                }
+                Err(self.reopen_db(db_name, e))
+            }
+        }
+    }
+
+    /// move the restored files of the db in place along with the settings of its tables, and
+    /// open the db
+    fn replace_db(
+        &self,
+        db_name: &str,
+        restoring_path: &Path,
+        rocks_db_path: &Path,
+        metadata: SystemRows,
+    ) -> Result<DBInstance, ConstDBError> {
+        if utils::fs::exists(restoring_path)? {
+            std::fs::rename(restoring_path, rocks_db_path)?;
+        }
+        self.replace_db_metadata(db_name, metadata)?;
+        self.forget_table_versions(db_name, None)?;
+        self.open(db_name)
+    }
+
+    /// replace the rows of the system db of the database and its tables
+    fn replace_db_metadata(&self, db_name: &str, metadata: SystemRows) -> Result<(), ConstDBError> {
+        let system_db = self.system_db()?.rocks_db()?;
+        let mut batch = WriteBatch::default();
+        for (k, _) in Self::db_metadata(system_db, db_name)? {
+            batch.delete(k);
+        }
+        for (k, v) in metadata {
+            batch.put(k, v);
+        }
+        system_db.write(batch)?;
         Ok(())
     }
 
+    /// re-open the db after a failed restore, returns the error of the restore
+    fn reopen_db(&mut self, db_name: &str, e: ConstDBError) -> ConstDBError {
+        let reopened = self
+            .forget_table_versions(db_name, None)
+            .and_then(|_| self.open(db_name));
+        match reopened {
+            Ok(db) => {
+                self.dbs.insert(db_name.to_owned(), db);
+            }
+            Err(reopen_e) => println!(
+                "failed to re-open db [{}] after a failed restore: {}",
+                db_name,
+                reopen_e.to_string()
+            ),
+        }
+        e
+    }
+
+    /// the rows of the system db of the database and its tables
+    fn db_metadata(system_db: &RocksDB, db_name: &str) -> Result<SystemRows, ConstDBError> {
+        let mut rows = Vec::new();
+        let db_meta_key = SystemKeys::db_meta_key(db_name).as_key();
+        if let Some(v) = system_db.get(db_meta_key.as_bytes())? {
+            rows.push((db_meta_key.into_bytes(), v));
+        }
+        for prefix in [
+            SystemKeys::table_meta_prefix(db_name),
+            SystemKeys::db_table_version_prefix(db_name),
+        ] {
+            let prefix = prefix.as_key();
+            let mut read_opts = ReadOptions::default();
+            build_upper_bound(prefix.as_bytes())
+                .into_iter()
+                .for_each(|upper_key| read_opts.set_iterate_upper_bound(upper_key));
+            let iter_mode = IteratorMode::From(prefix.as_ref(), Direction::Forward);
+            for result_kv in system_db.iterator_opt(iter_mode, read_opts) {
+                let (k, v) = result_kv?;
+                rows.push((k.into_vec(), v.into_vec()));
+            }
+        }
+        Ok(rows)
+    }
+
     fn open(&self, name: &str) -> Result<DBInstance, ConstDBError> {
         let path = Path::new(self.settings.root.as_str()).join(name);
         std::fs::create_dir_all(&path)?;
@@ -318,3 +1730,61 @@ impl Engine {
         Ok(db)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn engine(root: &Path) -> Engine {
+        Engine::new(Settings {
+            root: root.to_str().unwrap().to_owned(),
+            max_page_size: 1000,
+            max_scan_size: 1000,
+            max_snapshot_ttl: 60,
+            change_retention_secs: 60,
+            backup_dir: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn cloned_tables_resolve_under_the_new_name() {
+        let root = std::env::temp_dir().join(format!("constdb-clone-{}", std::process::id()));
+        let mut engine = engine(&root);
+        let table: TableSettings = serde_json::from_value(json!({
+            "name": "t",
+            "primary_keys": [{"name": "id", "data_type": "Int64"}]
+        }))
+        .unwrap();
+        for db_name in ["a", "ab"] {
+            engine.create_db(db_name).unwrap();
+            engine.create_table(db_name, &table).unwrap();
+        }
+        engine
+            .alter_table("a", "t", json!({"options": {"strict": true}}))
+            .unwrap();
+
+        engine.clone_db("a", "b").unwrap();
+        assert_eq!(
+            engine.get_table("b", "t").unwrap(),
+            engine.get_table("a", "t").unwrap()
+        );
+        let versions = engine.list_table_versions("b", "t").unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions, engine.list_table_versions("a", "t").unwrap());
+        // the db of a name starting with the name of the cloned db is not cloned along
+        assert_eq!(engine.list_table_versions("ab", "t").unwrap().len(), 1);
+
+        engine
+            .alter_table("b", "t", json!({"options": {"strict": false}}))
+            .unwrap();
+        assert!(engine.get_table("a", "t").unwrap().strict);
+        assert!(!engine.get_table("b", "t").unwrap().strict);
+        assert_eq!(engine.list_table_versions("a", "t").unwrap().len(), 2);
+        assert_eq!(engine.list_table_versions("b", "t").unwrap().len(), 3);
+
+        drop(engine);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use axum::http::StatusCode;
+use rocksdb::ErrorKind;
 
 use super::Id;
 
@@ -8,6 +9,14 @@ pub enum ConstDBError {
     NotFound(Id),
     InvalidStates(String),
     InvalidArguments(String),
+    /// the transaction conflicts with a concurrent write, and can be retried
+    Conflict(String),
+    /// the row does not meet the `If-Match` or `If-None-Match` of the request
+    PreconditionFailed(String),
+    /// the request is valid but cannot be applied to the row, e.g. a `test` of a json patch fails
+    Unprocessable(String),
+    /// the data requested is no longer kept, e.g. the changes are pruned
+    Gone(String),
 }
 
 impl ToString for ConstDBError {
@@ -17,6 +26,10 @@ impl ToString for ConstDBError {
             ConstDBError::NotFound(id) => format!("{} not found!", id.to_string()),
             ConstDBError::InvalidStates(msg) => msg.to_owned(),
             ConstDBError::InvalidArguments(msg) => msg.to_owned(),
+            ConstDBError::Conflict(msg) => msg.to_owned(),
+            ConstDBError::PreconditionFailed(msg) => msg.to_owned(),
+            ConstDBError::Unprocessable(msg) => msg.to_owned(),
+            ConstDBError::Gone(msg) => msg.to_owned(),
         }
     }
 }
@@ -28,6 +41,31 @@ impl ConstDBError {
             ConstDBError::NotFound(_) => StatusCode::NOT_FOUND,
             ConstDBError::InvalidStates(_msg) => StatusCode::INTERNAL_SERVER_ERROR,
             ConstDBError::InvalidArguments(_) => StatusCode::BAD_REQUEST,
+            ConstDBError::Conflict(_) => StatusCode::CONFLICT,
+            ConstDBError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            ConstDBError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ConstDBError::Gone(_) => StatusCode::GONE,
+        }
+    }
+}
+
+impl ConstDBError {
+    /// the same error with the message prefixed by the index of the operation of a batch
+    pub fn of_operation(self, index: usize) -> Self {
+        let prefix = |msg: String| format!("operation {}: {}", index, msg);
+        let operation = |id: Id| Id::Operation {
+            index,
+            id: Box::new(id),
+        };
+        match self {
+            ConstDBError::AlreadyExists(id) => ConstDBError::AlreadyExists(operation(id)),
+            ConstDBError::NotFound(id) => ConstDBError::NotFound(operation(id)),
+            ConstDBError::InvalidStates(msg) => ConstDBError::InvalidStates(prefix(msg)),
+            ConstDBError::InvalidArguments(msg) => ConstDBError::InvalidArguments(prefix(msg)),
+            ConstDBError::Conflict(msg) => ConstDBError::Conflict(prefix(msg)),
+            ConstDBError::PreconditionFailed(msg) => ConstDBError::PreconditionFailed(prefix(msg)),
+            ConstDBError::Unprocessable(msg) => ConstDBError::Unprocessable(prefix(msg)),
+            ConstDBError::Gone(msg) => ConstDBError::Gone(prefix(msg)),
         }
     }
 }
@@ -40,7 +78,12 @@ impl From<std::io::Error> for ConstDBError {
 
 impl From<rocksdb::Error> for ConstDBError {
     fn from(e: rocksdb::Error) -> Self {
-        ConstDBError::InvalidStates(format!("rocksdb failed: {}", e))
+        match e.kind() {
+            ErrorKind::Busy | ErrorKind::TryAgain => {
+                ConstDBError::Conflict(format!("conflict with a concurrent write: {}", e))
+            }
+            _ => ConstDBError::InvalidStates(format!("rocksdb failed: {}", e)),
+        }
     }
 }
 
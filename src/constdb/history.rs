@@ -0,0 +1,41 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// the length of the time suffix of the keys of the row history
+const TIME_LEN: usize = 8;
+
+/// milliseconds since the epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// the key of a previous version of a row, which is the primary key followed by the time the
+/// version was replaced at, so the versions of a row are in the order of time. the value is the
+/// stored row, or empty if the row did not exist.
+pub fn version_key(pk: &[u8], replaced_at: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(pk.len() + TIME_LEN);
+    key.extend_from_slice(pk);
+    key.extend_from_slice(&replaced_at.to_be_bytes());
+    key
+}
+
+/// the primary key and the time the version was replaced at of the key
+pub fn decode_version_key(key: &[u8]) -> Option<(&[u8], u64)> {
+    let pos = key.len().checked_sub(TIME_LEN)?;
+    let (pk, time) = key.split_at(pos);
+    Some((pk, u64::from_be_bytes(time.try_into().ok()?)))
+}
+
+/// a version of a row, valid within `[valid_from, valid_to)` in milliseconds since the epoch.
+/// `None` is unbounded, as the time a row was written before the history was kept is unknown.
+#[derive(Serialize)]
+pub struct RowVersion {
+    pub valid_from: Option<u64>,
+    pub valid_to: Option<u64>,
+    pub row: Map<String, Value>,
+}
@@ -1,8 +1,28 @@
 #[derive(Debug)]
 pub enum Id {
     Database(String),
-    Table { db: String, name: String },
+    Table {
+        db: String,
+        name: String,
+    },
+    Index {
+        db: String,
+        table: String,
+        name: String,
+    },
+    /// the values of a unique index, as a json object
+    IndexEntry {
+        index: String,
+        values: String,
+    },
     Data,
+    Snapshot(String),
+    Backup(u32),
+    /// the id within an operation of a batch of operations, by the index of the operation
+    Operation {
+        index: usize,
+        id: Box<Id>,
+    },
 }
 
 impl Id {
@@ -12,6 +32,14 @@ impl Id {
             name: name.to_owned(),
         }
     }
+
+    pub fn index(db: &str, table: &str, name: &str) -> Id {
+        Id::Index {
+            db: db.to_owned(),
+            table: table.to_owned(),
+            name: name.to_owned(),
+        }
+    }
 }
 
 impl ToString for Id {
@@ -19,6 +47,11 @@ impl ToString for Id {
         match self {
             Id::Database(name) => format!("database[{}]", name),
             Id::Table { db, name } => format!("table[{}.{}]", db, name),
+            Id::Index { db, table, name } => format!("index[{}.{}.{}]", db, table, name),
+            Id::IndexEntry { index, values } => format!("index[{}] entry {}", index, values),
+            Id::Snapshot(token) => format!("snapshot[{}]", token),
+            Id::Backup(id) => format!("backup[{}]", id),
+            Id::Operation { index, id } => format!("operation {}: {}", index, id.to_string()),
             _ => "data".to_owned(),
         }
     }
@@ -1,11 +1,24 @@
+mod backup;
+mod changes;
 mod db;
 mod engine;
 pub mod errors;
+mod history;
 mod ids;
+mod patch;
 mod pk;
+mod row;
+pub mod scan;
 mod schema;
+mod snapshot;
 mod system_db;
+mod update;
+mod writes;
 
+pub use backup::{Backups, ServerBackup};
+pub use changes::RowWatch;
 pub use engine::*;
 pub use ids::Id;
 pub use pk::*;
+pub use snapshot::HeldSnapshot;
+pub use writes::Precondition;
@@ -0,0 +1,287 @@
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::errors::ConstDBError;
+
+const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+
+/// a patch to an existing row
+pub enum RowPatch {
+    /// RFC 7396 json merge patch, objects are merged deeply and `null` removes the field
+    Merge(Map<String, Value>),
+    /// RFC 6902 json patch, the operations are applied in order
+    Json(Vec<PatchOperation>),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+impl RowPatch {
+    /// parse the patch of the content type
+    pub fn parse(content_type: &str, data: &[u8]) -> Result<Self, ConstDBError> {
+        let invalid =
+            |e: serde_json::Error| ConstDBError::InvalidArguments(format!("invalid patch: {}", e));
+        // the parameters of the content type, e.g. the charset, are ignored
+        match content_type.split(';').next().unwrap_or_default().trim() {
+            MERGE_PATCH_CONTENT_TYPE => match serde_json::from_slice(data).map_err(invalid)? {
+                Value::Object(patch) => Ok(RowPatch::Merge(patch)),
+                _ => Err(ConstDBError::InvalidArguments(
+                    "merge patch should be a json object".to_owned(),
+                )),
+            },
+            JSON_PATCH_CONTENT_TYPE => Ok(RowPatch::Json(
+                serde_json::from_slice(data).map_err(invalid)?,
+            )),
+            content_type => Err(ConstDBError::InvalidArguments(format!(
+                "unsupported content type: {}, should be either {} or {}",
+                content_type, MERGE_PATCH_CONTENT_TYPE, JSON_PATCH_CONTENT_TYPE
+            ))),
+        }
+    }
+
+    /// apply the patch to the row, the patched row should still be a json object
+    pub fn apply(&self, row: Map<String, Value>) -> Result<Map<String, Value>, ConstDBError> {
+        let mut doc = Value::Object(row);
+        match self {
+            RowPatch::Merge(patch) => merge_patch(&mut doc, patch),
+            RowPatch::Json(operations) => {
+                for (i, operation) in operations.iter().enumerate() {
+                    apply_operation(&mut doc, operation).map_err(|msg| {
+                        let msg = format!("operation {}: {}", i, msg);
+                        match operation {
+                            // the patch is valid, the row is just not the one expected
+                            PatchOperation::Test { .. } => ConstDBError::Unprocessable(msg),
+                            _ => ConstDBError::InvalidArguments(msg),
+                        }
+                    })?;
+                }
+            }
+        }
+        match doc {
+            Value::Object(row) => Ok(row),
+            _ => Err(ConstDBError::InvalidArguments(
+                "patched row should be a json object".to_owned(),
+            )),
+        }
+    }
+}
+
+fn merge_patch(target: &mut Value, patch: &Map<String, Value>) {
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (name, value) in patch {
+        match value {
+            Value::Null => {
+                target.remove(name);
+            }
+            Value::Object(patch) => {
+                merge_patch(target.entry(name.to_owned()).or_insert(Value::Null), patch)
+            }
+            value => {
+                target.insert(name.to_owned(), value.clone());
+            }
+        }
+    }
+}
+
+fn apply_operation(doc: &mut Value, operation: &PatchOperation) -> Result<(), String> {
+    match operation {
+        PatchOperation::Add { path, value } => add(doc, path, value.clone()),
+        PatchOperation::Remove { path } => remove(doc, path).map(|_| ()),
+        PatchOperation::Replace { path, value } => {
+            let target = doc
+                .pointer_mut(path)
+                .ok_or_else(|| format!("path [{}] not found", path))?;
+            *target = value.clone();
+            Ok(())
+        }
+        PatchOperation::Move { from, path } => {
+            if path.starts_with(from.as_str()) && path[from.len()..].starts_with('/') {
+                return Err(format!("cannot move [{}] into its child [{}]", from, path));
+            }
+            let value = remove(doc, from)?;
+            add(doc, path, value)
+        }
+        PatchOperation::Copy { from, path } => {
+            let value = doc
+                .pointer(from)
+                .ok_or_else(|| format!("path [{}] not found", from))?
+                .clone();
+            add(doc, path, value)
+        }
+        PatchOperation::Test { path, value } => match doc.pointer(path) {
+            Some(current) if current == value => Ok(()),
+            _ => Err(format!("test of path [{}] failed", path)),
+        },
+    }
+}
+
+/// add the value at the path, an existing field is replaced and `-` appends to an array
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    let (parent, token) = match split_path(path)? {
+        Some(split) => split,
+        None => {
+            *doc = value;
+            return Ok(());
+        }
+    };
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(object)) => {
+            object.insert(token, value);
+            Ok(())
+        }
+        Some(Value::Array(array)) if token == "-" => {
+            array.push(value);
+            Ok(())
+        }
+        Some(Value::Array(array)) => match array_index(&token, array.len() + 1) {
+            Some(i) => {
+                array.insert(i, value);
+                Ok(())
+            }
+            None => Err(format!("invalid array index of path [{}]", path)),
+        },
+        _ => Err(format!("parent of path [{}] not found", path)),
+    }
+}
+
+/// remove the value at the path, the value should exist
+fn remove(doc: &mut Value, path: &str) -> Result<Value, String> {
+    let (parent, token) = match split_path(path)? {
+        Some(split) => split,
+        None => return Err("cannot remove the whole row".to_owned()),
+    };
+    let removed = match doc.pointer_mut(parent) {
+        Some(Value::Object(object)) => object.remove(token.as_str()),
+        Some(Value::Array(array)) => array_index(&token, array.len()).map(|i| array.remove(i)),
+        _ => None,
+    };
+    removed.ok_or_else(|| format!("path [{}] not found", path))
+}
+
+/// split the json pointer into the pointer of the parent and the unescaped last token, returns
+/// `None` for the whole document
+fn split_path(path: &str) -> Result<Option<(&str, String)>, String> {
+    if path.is_empty() {
+        return Ok(None);
+    }
+    match path.rfind('/') {
+        Some(pos) if path.starts_with('/') => Ok(Some((
+            &path[..pos],
+            path[pos + 1..].replace("~1", "/").replace("~0", "~"),
+        ))),
+        _ => Err(format!("invalid path [{}], should start with '/'", path)),
+    }
+}
+
+/// the index of an array of the token, which should be less than `bound`
+fn array_index(token: &str, bound: usize) -> Option<usize> {
+    // leading zeros are not allowed
+    if token.len() > 1 && token.starts_with('0') {
+        return None;
+    }
+    token.parse().ok().filter(|i| *i < bound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn row(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    fn json_patch(operations: Value) -> RowPatch {
+        RowPatch::parse(JSON_PATCH_CONTENT_TYPE, operations.to_string().as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn merge_patches_merge_objects_deeply() {
+        let patch = RowPatch::parse(
+            "application/merge-patch+json; charset=utf-8",
+            br#"{"address": {"city": "Foo", "zip": null}, "age": null, "tags": ["b"]}"#,
+        )
+        .unwrap();
+        let patched = patch
+            .apply(row(json!({
+                "id": 1,
+                "age": 10,
+                "tags": ["a"],
+                "address": {"city": "Bar", "zip": "1"}
+            })))
+            .unwrap();
+        assert_eq!(
+            Value::Object(patched),
+            json!({"id": 1, "tags": ["b"], "address": {"city": "Foo"}})
+        );
+    }
+
+    #[test]
+    fn json_patches_apply_in_order() {
+        let patch = json_patch(json!([
+            {"op": "test", "path": "/address/city", "value": "Foo"},
+            {"op": "add", "path": "/tags", "value": ["a"]},
+            {"op": "add", "path": "/tags/-", "value": "c"},
+            {"op": "add", "path": "/tags/1", "value": "b"},
+            {"op": "move", "from": "/address/city", "path": "/city"},
+            {"op": "copy", "from": "/city", "path": "/a~1b"},
+            {"op": "replace", "path": "/age", "value": 11},
+            {"op": "remove", "path": "/address"}
+        ]));
+        let patched = patch
+            .apply(row(json!({"id": 1, "age": 10, "address": {"city": "Foo"}})))
+            .unwrap();
+        assert_eq!(
+            Value::Object(patched),
+            json!({"id": 1, "age": 11, "tags": ["a", "b", "c"], "city": "Foo", "a/b": "Foo"})
+        );
+    }
+
+    #[test]
+    fn failed_tests_are_unprocessable() {
+        let patch = json_patch(json!([
+            {"op": "add", "path": "/age", "value": 11},
+            {"op": "test", "path": "/age", "value": 10}
+        ]));
+        match patch.apply(row(json!({"age": 10}))) {
+            Err(ConstDBError::Unprocessable(msg)) => assert!(msg.starts_with("operation 1:")),
+            _ => panic!("test should fail"),
+        }
+    }
+
+    #[test]
+    fn invalid_operations_are_rejected() {
+        let doc = || row(json!({"a": {"b": 1}, "list": [1]}));
+        let invalid = [
+            json!([{"op": "remove", "path": "/missing"}]),
+            json!([{"op": "replace", "path": "/missing", "value": 1}]),
+            json!([{"op": "add", "path": "/missing/b", "value": 1}]),
+            json!([{"op": "add", "path": "/list/01", "value": 1}]),
+            json!([{"op": "add", "path": "/list/2", "value": 1}]),
+            json!([{"op": "move", "from": "/a", "path": "/a/c"}]),
+            json!([{"op": "add", "path": "a", "value": 1}]),
+            json!([{"op": "remove", "path": ""}]),
+            json!([{"op": "replace", "path": "", "value": 1}]),
+        ];
+        for operations in invalid {
+            match json_patch(operations.clone()).apply(doc()) {
+                Err(ConstDBError::InvalidArguments(_)) => {}
+                _ => panic!("{} should be rejected", operations),
+            }
+        }
+        assert!(RowPatch::parse("application/json", b"{}").is_err());
+        assert!(RowPatch::parse(MERGE_PATCH_CONTENT_TYPE, b"[]").is_err());
+    }
+}
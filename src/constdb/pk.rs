@@ -1,9 +1,21 @@
+use serde_json::{Number, Value};
+
 use super::errors::ConstDBError;
+use crate::protos::constdb_model::DataType;
+
+/// version of the on-disk primary key format, tables created with an older version are re-keyed
+/// on startup.
+pub const KEY_FORMAT_VERSION: u32 = 2;
 
 #[derive(Debug)]
 pub enum PrimaryKey {
     Complete(Vec<u8>),
     Prefix(Vec<u8>),
+    /// keys within `[lower, upper)`, there is no upper limit if `upper` is `None`
+    Range {
+        lower: Vec<u8>,
+        upper: Option<Vec<u8>>,
+    },
 }
 
 impl PrimaryKey {
@@ -11,15 +23,401 @@ impl PrimaryKey {
         match self {
             PrimaryKey::Complete(bytes) => bytes.as_slice(),
             PrimaryKey::Prefix(bytes) => bytes.as_slice(),
+            PrimaryKey::Range { lower, .. } => lower.as_slice(),
         }
     }
 
     pub fn complete(&self) -> Result<&[u8], ConstDBError> {
         match self {
             PrimaryKey::Complete(bytes) => Ok(bytes.as_slice()),
-            PrimaryKey::Prefix(_) => Err(ConstDBError::InvalidArguments(
-                "primary key not complete".to_owned(),
+            PrimaryKey::Prefix(_) | PrimaryKey::Range { .. } => Err(
+                ConstDBError::InvalidArguments("primary key not complete".to_owned()),
+            ),
+        }
+    }
+
+    /// whether the complete key is the key, or starts with the prefix, or is within the range
+    pub fn matches(&self, key: &[u8]) -> bool {
+        match self {
+            PrimaryKey::Complete(bytes) => key == bytes.as_slice(),
+            PrimaryKey::Prefix(bytes) => key.starts_with(bytes),
+            PrimaryKey::Range { lower, upper } => match upper {
+                Some(upper) => key >= lower.as_slice() && key < upper.as_slice(),
+                None => key >= lower.as_slice(),
+            },
+        }
+    }
+}
+
+/// the smallest key that is greater than every key starting with the prefix
+pub fn build_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    for pos in (0..prefix.len()).rev() {
+        let v = &prefix[pos];
+        if *v != 0xFF {
+            let mut stop_key = prefix[..=pos].to_owned();
+            stop_key[pos] = v + 1;
+            return Some(stop_key);
+        }
+    }
+    None
+}
+
+/// typed value of a primary key field
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    String(String),
+    Boolean(bool),
+    Int32(i32),
+    Int64(i64),
+    Float32(f32),
+    Float64(f64),
+    DateTime(String),
+}
+
+impl FieldValue {
+    /// append the memcomparable encoding of this value to the key
+    pub fn encode(&self, key: &mut Vec<u8>) {
+        match self {
+            FieldValue::String(s) => encoding::encode_str(s, key),
+            FieldValue::Boolean(b) => key.extend(encoding::encode_bool(*b)),
+            FieldValue::Int32(i) => key.extend(encoding::encode_i32(*i)),
+            FieldValue::Int64(i) => key.extend(encoding::encode_i64(*i)),
+            FieldValue::Float32(f) => key.extend(encoding::encode_f32(*f)),
+            FieldValue::Float64(f) => key.extend(encoding::encode_f64(*f)),
+            FieldValue::DateTime(s) => encoding::encode_str(s, key),
+        }
+    }
+
+    /// decode a value of the given type from the head of the key, returns the value and the
+    /// number of bytes consumed.
+    pub fn decode(data_type: DataType, key: &[u8]) -> Result<(FieldValue, usize), ConstDBError> {
+        match data_type {
+            DataType::String => {
+                encoding::decode_str(key).map(|(s, len)| (FieldValue::String(s), len))
+            }
+            DataType::DateTime => {
+                encoding::decode_str(key).map(|(s, len)| (FieldValue::DateTime(s), len))
+            }
+            DataType::Boolean => encoding::decode_bool(key).map(|b| (FieldValue::Boolean(b), 1)),
+            DataType::Int32 => encoding::decode_i32(key).map(|i| (FieldValue::Int32(i), 4)),
+            DataType::Int64 => encoding::decode_i64(key).map(|i| (FieldValue::Int64(i), 8)),
+            DataType::Float32 => encoding::decode_f32(key).map(|f| (FieldValue::Float32(f), 4)),
+            DataType::Float64 => encoding::decode_f64(key).map(|f| (FieldValue::Float64(f), 8)),
+            DataType::Unknown => Err(ConstDBError::InvalidStates(
+                "cannot decode primary key field of unknown type".to_owned(),
             )),
         }
     }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            FieldValue::String(s) => Value::String(s.to_owned()),
+            FieldValue::Boolean(b) => Value::Bool(*b),
+            FieldValue::Int32(i) => Value::from(*i),
+            FieldValue::Int64(i) => Value::from(*i),
+            FieldValue::Float32(f) => Number::from_f64(*f as f64)
+                .map(Value::Number)
+                .unwrap_or(Value::Null),
+            FieldValue::Float64(f) => Number::from_f64(*f)
+                .map(Value::Number)
+                .unwrap_or(Value::Null),
+            FieldValue::DateTime(s) => Value::String(s.to_owned()),
+        }
+    }
+}
+
+/// memcomparable encodings of the primary key fields, the byte order of the encoded values is the
+/// same as the natural order of the values.
+pub mod encoding {
+    use crate::constdb::errors::ConstDBError;
+
+    const SIGN_BIT_32: u32 = 1 << 31;
+    const SIGN_BIT_64: u64 = 1 << 63;
+    const ESCAPE: u8 = 0x00;
+    const ESCAPED_NUL: u8 = 0xFF;
+    const TERMINATOR: u8 = 0x01;
+
+    /// flip the sign bit so that negative numbers sort before positive ones
+    pub fn encode_i32(i: i32) -> Vec<u8> {
+        ((i as u32) ^ SIGN_BIT_32).to_be_bytes().to_vec()
+    }
+
+    pub fn encode_i64(i: i64) -> Vec<u8> {
+        ((i as u64) ^ SIGN_BIT_64).to_be_bytes().to_vec()
+    }
+
+    /// IEEE 754 total order: flip every bit of negative numbers and only the sign bit of positive
+    /// ones
+    pub fn encode_f32(f: f32) -> Vec<u8> {
+        let bits = f.to_bits();
+        let ordered = if bits & SIGN_BIT_32 != 0 {
+            !bits
+        } else {
+            bits ^ SIGN_BIT_32
+        };
+        ordered.to_be_bytes().to_vec()
+    }
+
+    pub fn encode_f64(f: f64) -> Vec<u8> {
+        let bits = f.to_bits();
+        let ordered = if bits & SIGN_BIT_64 != 0 {
+            !bits
+        } else {
+            bits ^ SIGN_BIT_64
+        };
+        ordered.to_be_bytes().to_vec()
+    }
+
+    pub fn encode_bool(b: bool) -> Vec<u8> {
+        vec![b as u8]
+    }
+
+    /// strings are self-delimiting: every `0x00` is escaped as `0x00 0xFF` and the string is
+    /// terminated by `0x00 0x01`, so a string always sorts before any of its extensions.
+    pub fn encode_str(s: &str, key: &mut Vec<u8>) {
+        for b in s.as_bytes() {
+            key.push(*b);
+            if *b == ESCAPE {
+                key.push(ESCAPED_NUL);
+            }
+        }
+        key.push(ESCAPE);
+        key.push(TERMINATOR);
+    }
+
+    fn fixed<const N: usize>(key: &[u8]) -> Result<[u8; N], ConstDBError> {
+        key.get(..N)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| ConstDBError::InvalidStates("primary key is truncated".to_owned()))
+    }
+
+    pub fn decode_i32(key: &[u8]) -> Result<i32, ConstDBError> {
+        Ok((u32::from_be_bytes(fixed(key)?) ^ SIGN_BIT_32) as i32)
+    }
+
+    pub fn decode_i64(key: &[u8]) -> Result<i64, ConstDBError> {
+        Ok((u64::from_be_bytes(fixed(key)?) ^ SIGN_BIT_64) as i64)
+    }
+
+    pub fn decode_f32(key: &[u8]) -> Result<f32, ConstDBError> {
+        let ordered = u32::from_be_bytes(fixed(key)?);
+        let bits = if ordered & SIGN_BIT_32 != 0 {
+            ordered ^ SIGN_BIT_32
+        } else {
+            !ordered
+        };
+        Ok(f32::from_bits(bits))
+    }
+
+    pub fn decode_f64(key: &[u8]) -> Result<f64, ConstDBError> {
+        let ordered = u64::from_be_bytes(fixed(key)?);
+        let bits = if ordered & SIGN_BIT_64 != 0 {
+            ordered ^ SIGN_BIT_64
+        } else {
+            !ordered
+        };
+        Ok(f64::from_bits(bits))
+    }
+
+    pub fn decode_bool(key: &[u8]) -> Result<bool, ConstDBError> {
+        match fixed::<1>(key)? {
+            [0x00] => Ok(false),
+            [0x01] => Ok(true),
+            [b] => Err(ConstDBError::InvalidStates(format!(
+                "invalid boolean in primary key: {}",
+                b
+            ))),
+        }
+    }
+
+    /// returns the decoded string and the number of bytes consumed, including the terminator
+    pub fn decode_str(key: &[u8]) -> Result<(String, usize), ConstDBError> {
+        let mut bytes = Vec::new();
+        let mut pos = 0;
+        loop {
+            match (key.get(pos), key.get(pos + 1)) {
+                (Some(&ESCAPE), Some(&ESCAPED_NUL)) => bytes.push(ESCAPE),
+                (Some(&ESCAPE), Some(&TERMINATOR)) => break,
+                (Some(&ESCAPE), _) | (None, _) => {
+                    return Err(ConstDBError::InvalidStates(
+                        "invalid string in primary key".to_owned(),
+                    ))
+                }
+                (Some(b), _) => {
+                    bytes.push(*b);
+                    pos += 1;
+                    continue;
+                }
+            }
+            pos += 2;
+        }
+        let s = String::from_utf8(bytes).map_err(|e| {
+            ConstDBError::InvalidStates(format!("invalid string in primary key: {}", e))
+        })?;
+        Ok((s, pos + 2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(values: &[FieldValue]) -> Vec<u8> {
+        let mut key = Vec::new();
+        values.iter().for_each(|v| v.encode(&mut key));
+        key
+    }
+
+    fn decode_all(data_types: &[DataType], key: &[u8]) -> Vec<FieldValue> {
+        let mut pos = 0;
+        let mut values = Vec::new();
+        for data_type in data_types {
+            let (value, len) = FieldValue::decode(*data_type, &key[pos..]).unwrap();
+            values.push(value);
+            pos += len;
+        }
+        assert_eq!(pos, key.len());
+        values
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = vec![
+            FieldValue::String("a\0b".to_owned()),
+            FieldValue::Boolean(true),
+            FieldValue::Int32(-7),
+            FieldValue::Int64(i64::MIN),
+            FieldValue::Float32(-1.5),
+            FieldValue::Float64(f64::MAX),
+            FieldValue::DateTime("2024-01-01T00:00:00Z".to_owned()),
+        ];
+        let data_types = [
+            DataType::String,
+            DataType::Boolean,
+            DataType::Int32,
+            DataType::Int64,
+            DataType::Float32,
+            DataType::Float64,
+            DataType::DateTime,
+        ];
+        assert_eq!(decode_all(&data_types, &key(&values)), values);
+    }
+
+    #[test]
+    fn integers_sort_across_the_sign() {
+        let ints = [i32::MIN, -256, -1, 0, 1, 255, i32::MAX];
+        for pair in ints.windows(2) {
+            assert!(encoding::encode_i32(pair[0]) < encoding::encode_i32(pair[1]));
+        }
+        let longs = [i64::MIN, -1, 0, 1, i64::MAX];
+        for pair in longs.windows(2) {
+            assert!(encoding::encode_i64(pair[0]) < encoding::encode_i64(pair[1]));
+        }
+        assert_eq!(encoding::encode_i32(0), vec![0x80, 0, 0, 0]);
+        assert_eq!(encoding::decode_i32(&encoding::encode_i32(-1)).unwrap(), -1);
+    }
+
+    #[test]
+    fn floats_sort_in_total_order() {
+        let floats = [
+            f64::NEG_INFINITY,
+            f64::MIN,
+            -1.0,
+            -f64::MIN_POSITIVE,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE,
+            1.0,
+            f64::MAX,
+            f64::INFINITY,
+        ];
+        for pair in floats.windows(2) {
+            assert!(encoding::encode_f64(pair[0]) < encoding::encode_f64(pair[1]));
+        }
+        let floats = [f32::NEG_INFINITY, -2.5, -0.0, 0.0, 2.5, f32::INFINITY];
+        for pair in floats.windows(2) {
+            assert!(encoding::encode_f32(pair[0]) < encoding::encode_f32(pair[1]));
+        }
+        for f in floats {
+            let decoded = encoding::decode_f32(&encoding::encode_f32(f)).unwrap();
+            assert_eq!(decoded.to_bits(), f.to_bits());
+        }
+    }
+
+    #[test]
+    fn strings_escape_nul_and_sort_before_extensions() {
+        let mut encoded = Vec::new();
+        encoding::encode_str("a\0", &mut encoded);
+        assert_eq!(encoded, vec![b'a', 0x00, 0xFF, 0x00, 0x01]);
+        assert_eq!(
+            encoding::decode_str(&encoded).unwrap(),
+            ("a\0".to_owned(), 5)
+        );
+
+        let strings = ["", "\0", "a", "a\0", "a\0b", "ab", "b"];
+        for pair in strings.windows(2) {
+            let (mut left, mut right) = (Vec::new(), Vec::new());
+            encoding::encode_str(pair[0], &mut left);
+            encoding::encode_str(pair[1], &mut right);
+            assert!(left < right, "{:?} < {:?}", pair[0], pair[1]);
+        }
+        assert!(encoding::decode_str(&[b'a', 0x00]).is_err());
+    }
+
+    #[test]
+    fn composite_keys_sort_by_the_first_field() {
+        let short = key(&[
+            FieldValue::String("a".to_owned()),
+            FieldValue::Int32(i32::MAX),
+        ]);
+        let long = key(&[
+            FieldValue::String("a\0".to_owned()),
+            FieldValue::Int32(i32::MIN),
+        ]);
+        assert!(short < long);
+    }
+
+    #[test]
+    fn upper_bounds() {
+        assert_eq!(build_upper_bound(&[0x01, 0x02]), Some(vec![0x01, 0x03]));
+        assert_eq!(build_upper_bound(&[0x01, 0xFF]), Some(vec![0x02]));
+        assert_eq!(build_upper_bound(&[0xFF, 0xFF]), None);
+        assert_eq!(build_upper_bound(&[]), None);
+
+        let prefix = key(&[FieldValue::String("a".to_owned())]);
+        let upper = build_upper_bound(&prefix).unwrap();
+        let within = key(&[
+            FieldValue::String("a".to_owned()),
+            FieldValue::Int32(i32::MAX),
+        ]);
+        let beyond = key(&[FieldValue::String("a\0".to_owned())]);
+        assert!(within < upper);
+        assert!(beyond >= upper);
+    }
+
+    #[test]
+    fn ranges_match_within_bounds() {
+        let range = PrimaryKey::Range {
+            lower: encoding::encode_i32(10),
+            upper: Some(encoding::encode_i32(20)),
+        };
+        assert!(range.matches(&encoding::encode_i32(10)));
+        assert!(range.matches(&encoding::encode_i32(19)));
+        assert!(!range.matches(&encoding::encode_i32(20)));
+        assert!(!range.matches(&encoding::encode_i32(-10)));
+
+        let unbounded = PrimaryKey::Range {
+            lower: encoding::encode_i32(10),
+            upper: None,
+        };
+        assert!(unbounded.matches(&encoding::encode_i32(i32::MAX)));
+        assert!(unbounded.complete().is_err());
+
+        let prefix = PrimaryKey::Prefix(key(&[FieldValue::String("a".to_owned())]));
+        assert!(prefix.matches(&key(&[
+            FieldValue::String("a".to_owned()),
+            FieldValue::Boolean(false)
+        ])));
+        assert!(!prefix.matches(&key(&[FieldValue::String("ab".to_owned())])));
+    }
 }
@@ -0,0 +1,629 @@
+use protobuf::{CodedInputStream, CodedOutputStream};
+use rocksdb::MergeOperands;
+use serde_json::{Map, Number, Value};
+
+use super::errors::ConstDBError;
+use crate::protos::constdb_model::{DataType, TableSettings};
+
+/// rows written as json are stored as `[ROW_FORMAT_JSON][schema version, u32 big endian][json]`
+const ROW_FORMAT_JSON: u8 = 0x01;
+/// rows are stored as `[ROW_FORMAT_BINARY][schema version, u32 big endian][message]`, where the
+/// message is in the protobuf wire format, see `encode_row`.
+const ROW_FORMAT_BINARY: u8 = 0x02;
+/// rows patched by the merge operator are stored as
+/// `[ROW_FORMAT_PATCHED][base length, u32 big endian][base row]` followed by the patches, each as
+/// `[patch length, u32 big endian][json row of the patched fields]`. the base row of an operand of
+/// the merge operator is the whole row to create if the row does not exist, it is empty for the
+/// operands written before the operands carried it.
+const ROW_FORMAT_PATCHED: u8 = 0x03;
+/// rows are stored as `[ROW_REVISION][revision, u64 big endian][row]` in any of the formats above.
+/// the revision increases on every write of the row, the rows written before the revisions have
+/// revision 0.
+const ROW_REVISION: u8 = 0x04;
+/// rows written before the tables were versioned are plain json objects of the first version
+const LEGACY_ROW_VERSION: u32 = 1;
+
+/// json object of the fields that are not declared, or not of the declared type
+const EXTRAS_FIELD: u32 = 1;
+/// field numbers of the declared fields that are null, packed
+const NULLS_FIELD: u32 = 2;
+/// the primary key fields are numbered from here in order, followed by the columns by their ids
+const FIRST_VALUE_FIELD: u32 = 3;
+
+/// the data of a stored row, to be read with the settings of the version that wrote it
+pub enum RowData<'a> {
+    Json(&'a [u8]),
+    Binary(&'a [u8]),
+}
+
+/// a row along with the patches merged into it, applied in order
+pub struct PatchedRow<'a> {
+    pub base: Option<&'a [u8]>,
+    pub patches: Vec<&'a [u8]>,
+}
+
+/// a declared field of the table with its field number in the binary row
+struct DeclaredField<'a> {
+    number: u32,
+    name: &'a str,
+    data_type: DataType,
+}
+
+/// encode the row as a binary row of the current version of the table
+pub fn encode_row(
+    table: &TableSettings,
+    row: &Map<String, Value>,
+) -> Result<Vec<u8>, ConstDBError> {
+    let fields = declared_fields(table);
+    let mut value = vec![ROW_FORMAT_BINARY];
+    value.extend(table.version.to_be_bytes());
+    let mut extras = Map::new();
+    let mut nulls = Vec::new();
+    {
+        let mut os = CodedOutputStream::vec(&mut value);
+        for (name, v) in row {
+            match fields.iter().find(|f| f.name == name) {
+                Some(field) if v.is_null() => nulls.push(field.number),
+                Some(field) if write_value(&mut os, field, v)? => {}
+                _ => {
+                    extras.insert(name.to_owned(), v.clone());
+                }
+            }
+        }
+        if !nulls.is_empty() {
+            os.write_repeated_packed_uint32(NULLS_FIELD, &nulls)?;
+        }
+        if !extras.is_empty() {
+            os.write_bytes(EXTRAS_FIELD, &serde_json::to_vec(&extras)?)?;
+        }
+        os.flush()?;
+    }
+    Ok(value)
+}
+
+/// returns the schema version and the data of a stored row
+pub fn decode_row(value: &[u8]) -> Result<(u32, RowData<'_>), ConstDBError> {
+    let format = match value.first() {
+        Some(&ROW_FORMAT_JSON) => RowData::Json,
+        Some(&ROW_FORMAT_BINARY) => RowData::Binary,
+        _ => return Ok((LEGACY_ROW_VERSION, RowData::Json(value))),
+    };
+    match value.get(1..5) {
+        Some(version) => Ok((
+            u32::from_be_bytes(version.try_into().unwrap()),
+            format(&value[5..]),
+        )),
+        None => Err(ConstDBError::InvalidStates("row is truncated".to_owned())),
+    }
+}
+
+/// prepend the revision to the encoded row
+pub fn encode_revision(revision: u64, row: &[u8]) -> Vec<u8> {
+    let mut value = vec![ROW_REVISION];
+    value.extend(revision.to_be_bytes());
+    value.extend(row);
+    value
+}
+
+/// returns the revision and the encoded row of a stored row
+pub fn decode_revision(value: &[u8]) -> Result<(u64, &[u8]), ConstDBError> {
+    if value.first() != Some(&ROW_REVISION) {
+        return Ok((0, value));
+    }
+    match value.get(1..9) {
+        Some(revision) => Ok((
+            u64::from_be_bytes(revision.try_into().unwrap()),
+            &value[9..],
+        )),
+        None => Err(ConstDBError::InvalidStates(
+            "revision of row is truncated".to_owned(),
+        )),
+    }
+}
+
+/// encode the fields of a patch as the operand of the merge operator, along with the whole row of
+/// the fields to create if the row does not exist, see `merge_row_patches`. the operand is of
+/// revision 1, which is added to the revision of the row by the merge.
+pub fn encode_row_patch(
+    table: &TableSettings,
+    patch: &Map<String, Value>,
+    row: &Map<String, Value>,
+) -> Result<Vec<u8>, ConstDBError> {
+    let base = encode_row(table, row)?;
+    let mut json = vec![ROW_FORMAT_JSON];
+    json.extend(table.version.to_be_bytes());
+    json.extend(serde_json::to_vec(patch)?);
+    let mut value = vec![ROW_FORMAT_PATCHED];
+    value.extend((base.len() as u32).to_be_bytes());
+    value.extend(base);
+    value.extend((json.len() as u32).to_be_bytes());
+    value.extend(json);
+    Ok(encode_revision(1, &value))
+}
+
+/// returns the base row and the patches of a patched row, or `None` if the row is not patched
+pub fn decode_patched_row(value: &[u8]) -> Result<Option<PatchedRow<'_>>, ConstDBError> {
+    if value.first() != Some(&ROW_FORMAT_PATCHED) {
+        return Ok(None);
+    }
+    let truncated = || ConstDBError::InvalidStates("patched row is truncated".to_owned());
+    let mut parts = Vec::new();
+    let mut rest = &value[1..];
+    while !rest.is_empty() {
+        let len = rest.get(..4).ok_or_else(truncated)?;
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        parts.push(rest.get(4..4 + len).ok_or_else(truncated)?);
+        rest = &rest[4 + len..];
+    }
+    let base = match parts.first() {
+        Some(base) => *base,
+        None => return Err(truncated()),
+    };
+    Ok(Some(PatchedRow {
+        base: (!base.is_empty()).then_some(base),
+        patches: parts[1..].to_vec(),
+    }))
+}
+
+/// the merge operator of the tables, the patches are appended to the existing row without being
+/// decoded, so that it works without the settings of the table. the patches are resolved against
+/// the current version of the table on read, and the row is stored as a whole again by the next
+/// write that is not a patch. as the patches of the same version are of the same fields, the
+/// patches are merged into the previous one of the same version. the revisions of the operands
+/// are added to the revision of the row, so that the merge is associative.
+///
+/// a missing or deleted row is created from the base of the first operand, which holds the fields
+/// of the patches of the operand as well. an operand that cannot be decoded is skipped, and a row
+/// that cannot be decoded is kept as it is, so that the merge never fails and never makes the row
+/// unreadable.
+pub fn merge_row_patches(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    Some(merge_patches(existing, operands))
+}
+
+/// merge the operands into the existing row, see `merge_row_patches`
+fn merge_patches<'a>(
+    existing: Option<&[u8]>,
+    operands: impl IntoIterator<Item = &'a [u8]>,
+) -> Vec<u8> {
+    let (mut revision, mut base, mut patches) = match existing.map(decode_stored_patches) {
+        None => (0, None, Vec::new()),
+        Some(Some((revision, patched))) => {
+            (revision, patched.base, to_owned_patches(&patched.patches))
+        }
+        Some(None) => return existing.unwrap_or_default().to_vec(),
+    };
+    let mut has_base = existing.is_some();
+    for (operand_revision, operand) in operands.into_iter().filter_map(decode_operand) {
+        revision += operand_revision;
+        if !has_base {
+            base = operand.base;
+            has_base = true;
+        }
+        for patch in operand.patches {
+            let merged = match patches.last() {
+                Some(last) if last.get(..5) == patch.get(..5) => merge_json_patches(last, patch),
+                _ => None,
+            };
+            match merged {
+                Some(merged) => *patches.last_mut().unwrap() = merged,
+                None => patches.push(patch.to_vec()),
+            }
+        }
+    }
+
+    let base = base.unwrap_or_default();
+    let mut value = vec![ROW_FORMAT_PATCHED];
+    value.extend((base.len() as u32).to_be_bytes());
+    value.extend(base);
+    for patch in patches {
+        value.extend((patch.len() as u32).to_be_bytes());
+        value.extend(patch);
+    }
+    encode_revision(revision, &value)
+}
+
+/// the revision of a stored row along with the row as a patched row, `None` if the row cannot be
+/// decoded
+fn decode_stored_patches(value: &[u8]) -> Option<(u64, PatchedRow<'_>)> {
+    let (revision, value) = decode_revision(value).ok()?;
+    match decode_patched_row(value).ok()? {
+        Some(patched) => Some((revision, patched)),
+        None => Some((
+            revision,
+            PatchedRow {
+                base: Some(value),
+                patches: Vec::new(),
+            },
+        )),
+    }
+}
+
+/// the revision and the patches of an operand, `None` if the operand is not a patched row whose
+/// patches are json objects
+fn decode_operand(operand: &[u8]) -> Option<(u64, PatchedRow<'_>)> {
+    let (revision, operand) = decode_revision(operand).ok()?;
+    let patched = decode_patched_row(operand).ok()??;
+    for patch in &patched.patches {
+        match decode_row(patch).ok()? {
+            (_, RowData::Json(json)) if patch.first() == Some(&ROW_FORMAT_JSON) => {
+                serde_json::from_slice::<Map<String, Value>>(json).ok()?;
+            }
+            _ => return None,
+        }
+    }
+    Some((revision, patched))
+}
+
+fn to_owned_patches(patches: &[&[u8]]) -> Vec<Vec<u8>> {
+    patches.iter().map(|patch| patch.to_vec()).collect()
+}
+
+/// merge two json patches of the same version, the fields of the later one win
+fn merge_json_patches(patch: &[u8], later: &[u8]) -> Option<Vec<u8>> {
+    let mut fields: Map<String, Value> = serde_json::from_slice(patch.get(5..)?).ok()?;
+    let later_fields: Map<String, Value> = serde_json::from_slice(later.get(5..)?).ok()?;
+    fields.extend(later_fields);
+    let mut value = patch[..5].to_vec();
+    value.extend(serde_json::to_vec(&fields).ok()?);
+    Some(value)
+}
+
+/// decode a binary row with the settings of the version that wrote it
+pub fn decode_binary_row(
+    table: &TableSettings,
+    message: &[u8],
+) -> Result<Map<String, Value>, ConstDBError> {
+    let fields = declared_fields(table);
+    let mut row = Map::new();
+    let mut is = CodedInputStream::from_bytes(message);
+    while !is.eof()? {
+        let (number, wire_type) = is.read_tag_unpack()?;
+        match number {
+            EXTRAS_FIELD => {
+                let extras: Map<String, Value> = serde_json::from_slice(&is.read_bytes()?)?;
+                row.extend(extras);
+            }
+            NULLS_FIELD => {
+                let mut nulls = Vec::new();
+                is.read_repeated_packed_uint32_into(&mut nulls)?;
+                for field in fields.iter().filter(|f| nulls.contains(&f.number)) {
+                    row.insert(field.name.to_owned(), Value::Null);
+                }
+            }
+            number => match fields.iter().find(|f| f.number == number) {
+                Some(field) => {
+                    row.insert(field.name.to_owned(), read_value(&mut is, field)?);
+                }
+                None => is.skip_field(wire_type)?,
+            },
+        }
+    }
+    Ok(row)
+}
+
+/// the number as a float32, `None` if it overflows, or is too small to be anything but 0
+pub fn to_f32(f: f64) -> Option<f32> {
+    let f32_value = f as f32;
+    match f32_value.is_finite() && (f32_value != 0.0 || f == 0.0) {
+        true => Some(f32_value),
+        false => None,
+    }
+}
+
+/// the primary keys of a table never change, so the field numbers of the columns are stable
+/// across the versions of the table.
+fn declared_fields(table: &TableSettings) -> Vec<DeclaredField<'_>> {
+    let pk_fields = table
+        .primary_keys
+        .iter()
+        .enumerate()
+        .map(|(i, k)| DeclaredField {
+            number: FIRST_VALUE_FIELD + i as u32,
+            name: k.name.as_str(),
+            data_type: k.data_type.enum_value_or(DataType::Unknown),
+        });
+    let first_column_field = FIRST_VALUE_FIELD + table.primary_keys.len() as u32 - 1;
+    let column_fields = table.columns.iter().map(|c| DeclaredField {
+        number: first_column_field + c.id,
+        name: c.name.as_str(),
+        data_type: c.data_type.enum_value_or(DataType::Unknown),
+    });
+    pk_fields.chain(column_fields).collect()
+}
+
+/// write the value of a declared field, returns false if the value is not of the declared type
+fn write_value(
+    os: &mut CodedOutputStream,
+    field: &DeclaredField,
+    value: &Value,
+) -> Result<bool, ConstDBError> {
+    let number = field.number;
+    match (field.data_type, value) {
+        (DataType::String | DataType::DateTime, Value::String(s)) => os.write_string(number, s)?,
+        (DataType::Boolean, Value::Bool(b)) => os.write_bool(number, *b)?,
+        (DataType::Int32, Value::Number(v)) => {
+            match v.as_i64().and_then(|i| i32::try_from(i).ok()) {
+                Some(i) => os.write_sint32(number, i)?,
+                None => return Ok(false),
+            }
+        }
+        (DataType::Int64, Value::Number(v)) => match v.as_i64() {
+            Some(i) => os.write_sint64(number, i)?,
+            None => return Ok(false),
+        },
+        (DataType::Float32, Value::Number(v)) => match v.as_f64().and_then(to_f32) {
+            Some(f) => os.write_float(number, f)?,
+            None => return Ok(false),
+        },
+        (DataType::Float64, Value::Number(v)) => match v.as_f64() {
+            Some(f) => os.write_double(number, f)?,
+            None => return Ok(false),
+        },
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn read_value(is: &mut CodedInputStream, field: &DeclaredField) -> Result<Value, ConstDBError> {
+    let value = match field.data_type {
+        DataType::String | DataType::DateTime => Value::String(is.read_string()?),
+        DataType::Boolean => Value::Bool(is.read_bool()?),
+        DataType::Int32 => Value::from(is.read_sint32()?),
+        DataType::Int64 => Value::from(is.read_sint64()?),
+        // the shortest representation of the f32 is parsed back, so that 0.1 is read as 0.1
+        // instead of 0.10000000149011612
+        DataType::Float32 => float_value(is.read_float()?.to_string().parse().unwrap()),
+        DataType::Float64 => float_value(is.read_double()?),
+        DataType::Unknown => {
+            return Err(ConstDBError::InvalidStates(format!(
+                "field [{}] has an unknown data type",
+                field.name
+            )))
+        }
+    };
+    Ok(value)
+}
+
+fn float_value(f: f64) -> Value {
+    Number::from_f64(f)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::constdb_model::{Column, Field};
+    use serde_json::json;
+
+    fn table() -> TableSettings {
+        let mut table = TableSettings::new();
+        table.name = "t".to_owned();
+        table.version = 3;
+        table.primary_keys.push(Field {
+            name: "id".to_owned(),
+            data_type: DataType::Int64.into(),
+            ..Default::default()
+        });
+        let columns = [
+            ("name", DataType::String),
+            ("active", DataType::Boolean),
+            ("count", DataType::Int32),
+            ("ratio", DataType::Float32),
+            ("score", DataType::Float64),
+        ];
+        for (id, (name, data_type)) in columns.into_iter().enumerate() {
+            table.columns.push(Column {
+                name: name.to_owned(),
+                data_type: data_type.into(),
+                nullable: true,
+                id: id as u32 + 1,
+                ..Default::default()
+            });
+        }
+        table
+    }
+
+    fn round_trip(row: Value) -> Map<String, Value> {
+        let table = table();
+        let value = encode_row(&table, row.as_object().unwrap()).unwrap();
+        match decode_row(&value).unwrap() {
+            (3, RowData::Binary(message)) => decode_binary_row(&table, message).unwrap(),
+            _ => panic!("not a binary row of version 3"),
+        }
+    }
+
+    fn patch(table: &TableSettings, patch: Value) -> Vec<u8> {
+        let mut row = json!({"id": 1}).as_object().unwrap().clone();
+        row.extend(patch.as_object().unwrap().clone());
+        encode_row_patch(table, patch.as_object().unwrap(), &row).unwrap()
+    }
+
+    /// the revision, the base and the json of the patches of a merged row
+    fn patched(value: &[u8]) -> (u64, Option<Vec<u8>>, Vec<Value>) {
+        let (revision, value) = decode_revision(value).unwrap();
+        let patched = decode_patched_row(value).unwrap().unwrap();
+        let patches = patched
+            .patches
+            .iter()
+            .map(|patch| serde_json::from_slice(&patch[5..]).unwrap())
+            .collect();
+        (revision, patched.base.map(|base| base.to_vec()), patches)
+    }
+
+    #[test]
+    fn binary_rows_round_trip() {
+        let row = json!({
+            "id": -42,
+            "name": "a\u{0}b",
+            "active": true,
+            "count": i32::MIN,
+            "score": 1e300
+        });
+        assert_eq!(Value::Object(round_trip(row.clone())), row);
+    }
+
+    #[test]
+    fn float32_reads_back_its_shortest_representation() {
+        let row = round_trip(json!({"id": 1, "ratio": 0.1}));
+        assert_eq!(row.get("ratio"), Some(&json!(0.1)));
+    }
+
+    #[test]
+    fn float32_out_of_range_is_kept_as_extras() {
+        assert_eq!(to_f32(0.0), Some(0.0));
+        assert_eq!(to_f32(1e-50), None);
+        assert_eq!(to_f32(1e50), None);
+        let ratio = round_trip(json!({"id": 1, "ratio": 1e-50}))["ratio"]
+            .as_f64()
+            .unwrap();
+        assert!((ratio / 1e-50 - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn extras_and_nulls_are_kept() {
+        let row = json!({
+            "id": 1,
+            "name": null,
+            "count": 1i64 << 40,
+            "active": "yes",
+            "tags": ["a", "b"]
+        });
+        assert_eq!(Value::Object(round_trip(row.clone())), row);
+    }
+
+    #[test]
+    fn legacy_rows_are_json_of_the_first_version() {
+        let value = br#"{"id":1}"#;
+        match decode_row(value).unwrap() {
+            (LEGACY_ROW_VERSION, RowData::Json(json)) => assert_eq!(json, value),
+            _ => panic!("not a legacy row"),
+        }
+        assert!(decode_row(&[ROW_FORMAT_BINARY, 0]).is_err());
+    }
+
+    #[test]
+    fn patches_merge_associatively() {
+        let table = table();
+        let base = encode_revision(
+            5,
+            &encode_row(&table, json!({"id": 1}).as_object().unwrap()).unwrap(),
+        );
+        let a = patch(&table, json!({"name": "a", "count": 1}));
+        let b = patch(&table, json!({"name": "b"}));
+        let c = patch(&table, json!({"score": 2.0}));
+
+        let all_at_once = merge_patches(Some(&base), [&a[..], &b[..], &c[..]]);
+        let one_by_one = merge_patches(Some(&base), [&a[..]]);
+        let one_by_one = merge_patches(Some(&one_by_one), [&b[..]]);
+        let one_by_one = merge_patches(Some(&one_by_one), [&c[..]]);
+        let partial = merge_patches(None, [&b[..], &c[..]]);
+        let partial = merge_patches(None, [&a[..], &partial[..]]);
+        let grouped = merge_patches(Some(&base), [&partial[..]]);
+        assert_eq!(all_at_once, one_by_one);
+        assert_eq!(all_at_once, grouped);
+
+        let (revision, merged_base, patches) = patched(&all_at_once);
+        assert_eq!(revision, 8);
+        assert_eq!(
+            merged_base.as_deref(),
+            decode_revision(&base).ok().map(|r| r.1)
+        );
+        assert_eq!(
+            patches,
+            vec![json!({"name": "b", "count": 1, "score": 2.0})]
+        );
+    }
+
+    #[test]
+    fn patches_of_other_versions_are_kept_apart() {
+        let mut table = table();
+        let a = patch(&table, json!({"name": "a"}));
+        table.version += 1;
+        let b = patch(&table, json!({"name": "b"}));
+        let merged = merge_patches(None, [&a[..], &b[..]]);
+        let (revision, base, patches) = patched(&merged);
+        assert_eq!(revision, 2);
+        assert_eq!(
+            base.as_deref(),
+            decode_revision(&a)
+                .ok()
+                .and_then(|(_, a)| decode_patched_row(a).ok()?)
+                .and_then(|a| a.base)
+        );
+        assert_eq!(patches, vec![json!({"name": "a"}), json!({"name": "b"})]);
+    }
+
+    #[test]
+    fn missing_rows_are_created_from_the_base_of_the_first_operand() {
+        let table = table();
+        let a = patch(&table, json!({"name": "a"}));
+        let b = patch(&table, json!({"count": 2}));
+        let (revision, base, patches) = patched(&merge_patches(None, [&a[..], &b[..]]));
+        assert_eq!(revision, 2);
+        let base = base.unwrap();
+        match decode_row(&base).unwrap() {
+            (3, RowData::Binary(message)) => assert_eq!(
+                Value::Object(decode_binary_row(&table, message).unwrap()),
+                json!({"id": 1, "name": "a"})
+            ),
+            _ => panic!("not a binary row of version 3"),
+        }
+        assert_eq!(patches, vec![json!({"name": "a", "count": 2})]);
+    }
+
+    #[test]
+    fn corrupt_operands_never_poison_the_row() {
+        let table = table();
+        let base = encode_revision(
+            5,
+            &encode_row(&table, json!({"id": 1}).as_object().unwrap()).unwrap(),
+        );
+        let a = patch(&table, json!({"name": "a"}));
+        let mut corrupt = a.clone();
+        corrupt.truncate(corrupt.len() - 2);
+        let not_json = encode_revision(
+            1,
+            &[&[
+                ROW_FORMAT_PATCHED,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                6,
+                ROW_FORMAT_JSON,
+                0,
+                0,
+                0,
+                3,
+                b'{',
+            ][..]]
+            .concat(),
+        );
+
+        let merged = merge_patches(Some(&base), [&corrupt[..], &a[..], &not_json[..]]);
+        assert_eq!(merged, merge_patches(Some(&base), [&a[..]]));
+        let (revision, _, patches) = patched(&merged);
+        assert_eq!(revision, 6);
+        assert_eq!(patches, vec![json!({"name": "a"})]);
+
+        // a row that cannot be decoded is kept as it is
+        let broken = [ROW_REVISION, 0];
+        assert_eq!(merge_patches(Some(&broken), [&a[..]]), broken.to_vec());
+    }
+
+    #[test]
+    fn revisions_round_trip() {
+        let value = encode_revision(7, b"row");
+        assert_eq!(decode_revision(&value).unwrap(), (7, &b"row"[..]));
+        assert_eq!(decode_revision(b"row").unwrap(), (0, &b"row"[..]));
+        assert!(decode_revision(&[ROW_REVISION, 0]).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use rocksdb::Direction;
+
+use super::errors::ConstDBError;
+
+/// paging options of a scan
+#[derive(Clone)]
+pub struct ScanOptions {
+    /// max number of rows in a page
+    pub limit: usize,
+    /// the last key of the previous page
+    pub cursor: Option<Vec<u8>>,
+    /// keys are scanned in descending order with `Direction::Reverse`
+    pub direction: Direction,
+    /// the token of the snapshot to read at
+    pub snapshot: Option<String>,
+}
+
+impl ScanOptions {
+    pub fn from_params(
+        params: &HashMap<String, String>,
+        max_page_size: usize,
+    ) -> Result<Self, ConstDBError> {
+        let limit = match params.get("limit") {
+            Some(limit) => match limit.parse::<usize>() {
+                Ok(0) | Err(_) => {
+                    return Err(ConstDBError::InvalidArguments(format!(
+                        "invalid limit: {}",
+                        limit
+                    )))
+                }
+                Ok(limit) => limit.min(max_page_size),
+            },
+            None => max_page_size,
+        };
+        let cursor = params
+            .get("cursor")
+            .map(|cursor| decode_cursor(cursor))
+            .transpose()?;
+        let direction = match params.get("order").map(|order| order.as_str()) {
+            None | Some("asc") => Direction::Forward,
+            Some("desc") => Direction::Reverse,
+            Some(order) => {
+                return Err(ConstDBError::InvalidArguments(format!(
+                    "invalid order: {}, should be either asc or desc",
+                    order
+                )))
+            }
+        };
+        Ok(ScanOptions {
+            limit,
+            cursor,
+            direction,
+            snapshot: params.get("snapshot").cloned(),
+        })
+    }
+}
+
+impl ScanOptions {
+    /// the options of the page after the `next_cursor` of the page read with these options
+    pub fn next_page(&self, limit: usize, next_cursor: &str) -> Result<Self, ConstDBError> {
+        Ok(ScanOptions {
+            limit,
+            cursor: Some(decode_cursor(next_cursor)?),
+            direction: self.direction,
+            snapshot: self.snapshot.clone(),
+        })
+    }
+}
+
+pub fn encode_cursor(key: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(key)
+}
+
+fn decode_cursor(cursor: &str) -> Result<Vec<u8>, ConstDBError> {
+    URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|e| ConstDBError::InvalidArguments(format!("invalid cursor: {}", e)))
+}
+
+/// a page of rows, `next_cursor` is set when there are more rows after this page
+pub struct Page {
+    pub rows: Vec<String>,
+    pub next_cursor: Option<String>,
+}
+
+impl Page {
+    pub fn to_json(&self) -> Result<String, ConstDBError> {
+        Ok(format!(
+            "{{\"rows\":[{}],\"next_cursor\":{}}}",
+            self.rows.join(","),
+            serde_json::to_string(&self.next_cursor)?
+        ))
+    }
+}
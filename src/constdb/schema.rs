@@ -1,34 +1,549 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use crate::protos::constdb_model::{DataType, TableSettings};
+use crate::protos::constdb_model::{
+    AlterTable, Column, DataType, Index, RowHistory, TableSettings,
+};
 use crate::{constdb::errors::ConstDBError, protos::constdb_model::Field};
 use axum::body::Bytes;
-use serde_json::{Map, Value};
+use serde_json::{json, Map, Value};
 
-use super::PrimaryKey;
+use super::history::now_millis;
+use super::row::{
+    decode_binary_row, decode_patched_row, decode_revision, decode_row, encode_revision,
+    encode_row, encode_row_patch, to_f32, RowData,
+};
+use super::{build_upper_bound, FieldValue, PrimaryKey, KEY_FORMAT_VERSION};
+
+const RANGE_OPERATORS: [&str; 4] = ["gt", "gte", "lt", "lte"];
 
 pub struct SchemaHelper {
     table_settings: TableSettings,
+    /// all the versions of the table settings, used to resolve the rows written by older versions
+    history: Arc<Vec<TableSettings>>,
 }
 
 impl SchemaHelper {
     pub fn new(table_settings: TableSettings) -> Self {
-        SchemaHelper { table_settings }
+        SchemaHelper {
+            table_settings,
+            history: Arc::new(Vec::new()),
+        }
+    }
+
+    pub fn with_history(mut self, history: Arc<Vec<TableSettings>>) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// settings of a new table, the columns are numbered and the table starts from version 1
+    pub fn init_settings(input: &TableSettings) -> Result<TableSettings, ConstDBError> {
+        // `#` separates the table and the index in the names of the column families of indexes
+        if input.name.contains('#') {
+            return Err(ConstDBError::InvalidArguments(
+                "table name cannot contain '#'".to_owned(),
+            ));
+        }
+        Self::validate_settings(input)?;
+        let mut table = input.clone();
+        table.key_version = KEY_FORMAT_VERSION;
+        Self::upgrade_settings(&mut table);
+        table.row_history.clear();
+        if let Some(row_history) = input.row_history.as_ref() {
+            Self::set_row_history(&mut table, row_history);
+        }
+        Ok(table)
+    }
+
+    /// number the columns of the tables created before the tables were versioned
+    pub fn upgrade_settings(table: &mut TableSettings) {
+        for (i, column) in table.columns.iter_mut().enumerate() {
+            column.id = i as u32 + 1;
+        }
+        table.next_column_id = table.columns.len() as u32 + 1;
+        table.version = 1;
+    }
+
+    /// parse the alteration of the table, the fields of the options and the row history not given
+    /// are filled with the current ones, so that only the fields given are changed
+    pub fn parse_alteration(&self, mut input: Value) -> Result<AlterTable, ConstDBError> {
+        let table = &self.table_settings;
+        let current = [
+            (
+                "options",
+                json!({"strict": table.strict, "change_feed": table.change_feed}),
+            ),
+            (
+                "row_history",
+                serde_json::to_value(table.row_history.as_ref().cloned().unwrap_or_default())?,
+            ),
+        ];
+        if let Value::Object(fields) = &mut input {
+            for (name, current) in current {
+                if let (Some(Value::Object(given)), Value::Object(current)) =
+                    (fields.get_mut(name), current)
+                {
+                    for (field, value) in current {
+                        given.entry(field).or_insert(value);
+                    }
+                }
+            }
+        }
+        serde_json::from_value(input)
+            .map_err(|e| ConstDBError::InvalidArguments(format!("invalid alteration: {}", e)))
+    }
+
+    /// apply the alteration to the table settings, returns the next version of the settings
+    pub fn alter(&self, alteration: &AlterTable) -> Result<TableSettings, ConstDBError> {
+        let mut table = self.table_settings.clone();
+        for name in &alteration.drop_columns {
+            let pos = Self::column_position(&table, name)?;
+            if let Some(index) = table
+                .indexes
+                .iter()
+                .find(|i| i.fields.iter().any(|f| &f.name == name))
+            {
+                return Err(ConstDBError::InvalidArguments(format!(
+                    "column [{}] is used by index [{}], drop the index first",
+                    name, index.name
+                )));
+            }
+            table.columns.remove(pos);
+        }
+        for rename in &alteration.rename_columns {
+            let pos = Self::column_position(&table, rename.from.as_str())?;
+            table.columns[pos].name = rename.to.to_owned();
+            // the rows are resolved with the new name, so the indexed values stay the same
+            for index in table.indexes.iter_mut() {
+                for field in index.fields.iter_mut().filter(|f| f.name == rename.from) {
+                    field.name = rename.to.to_owned();
+                }
+            }
+        }
+        for default in &alteration.set_defaults {
+            let pos = Self::column_position(&table, default.name.as_str())?;
+            table.columns[pos].default_value = default.default_value.to_owned();
+        }
+        for column in &alteration.add_columns {
+            // the rows written before cannot have the new column
+            if !column.nullable && column.default_value.is_empty() {
+                return Err(ConstDBError::InvalidArguments(format!(
+                    "new column [{}] should be either nullable or have a default value",
+                    column.name
+                )));
+            }
+            let mut column = column.clone();
+            column.id = table.next_column_id;
+            table.next_column_id += 1;
+            table.columns.push(column);
+        }
+        if let Some(options) = alteration.options.as_ref() {
+            table.strict = options.strict;
+            table.change_feed = options.change_feed;
+        }
+        if let Some(row_history) = alteration.row_history.as_ref() {
+            Self::set_row_history(&mut table, row_history);
+        }
+        Self::validate_settings(&table)?;
+        table.version += 1;
+        Ok(table)
+    }
+
+    /// the history stays kept from the time it was enabled, unless it is disabled
+    fn set_row_history(table: &mut TableSettings, row_history: &RowHistory) {
+        let mut row_history = row_history.clone();
+        row_history.enabled_at = match table.row_history.as_ref() {
+            _ if !row_history.enabled => 0,
+            Some(current) if current.enabled => current.enabled_at,
+            _ => now_millis(),
+        };
+        table.row_history = Some(row_history).into();
+    }
+
+    fn column_position(table: &TableSettings, name: &str) -> Result<usize, ConstDBError> {
+        table
+            .columns
+            .iter()
+            .position(|c| c.name == name)
+            .ok_or_else(|| ConstDBError::InvalidArguments(format!("column [{}] not found", name)))
+    }
+
+    /// the settings with the new index added
+    pub fn add_index(&self, index: &Index) -> Result<TableSettings, ConstDBError> {
+        let mut table = self.table_settings.clone();
+        table.indexes.push(index.clone());
+        Self::validate_settings(&table)?;
+        table.version += 1;
+        Ok(table)
+    }
+
+    pub fn index(&self, name: &str) -> Option<&Index> {
+        self.table_settings.indexes.iter().find(|i| i.name == name)
+    }
+
+    /// the row history of the table, `None` if the history is not kept
+    pub fn row_history(&self) -> Option<&RowHistory> {
+        self.table_settings
+            .row_history
+            .as_ref()
+            .filter(|h| h.enabled)
+    }
+
+    pub fn table_settings(&self) -> &TableSettings {
+        &self.table_settings
+    }
+
+    /// check the declared columns of a new table
+    pub fn validate_settings(table_settings: &TableSettings) -> Result<(), ConstDBError> {
+        let mut errors = Vec::new();
+        let mut names: Vec<&str> = table_settings
+            .primary_keys
+            .iter()
+            .map(|k| k.name.as_str())
+            .collect();
+        for column in &table_settings.columns {
+            let data_type = column.data_type.enum_value_or(DataType::Unknown);
+            if column.name.is_empty() {
+                errors.push("column name cannot be empty".to_owned());
+            } else if names.contains(&column.name.as_str()) {
+                errors.push(format!(
+                    "column [{}] is declared more than once",
+                    column.name
+                ));
+            }
+            names.push(column.name.as_str());
+            if data_type == DataType::Unknown {
+                errors.push(format!("column [{}] has an unknown data type", column.name));
+                continue;
+            }
+            match Self::default_value(column) {
+                Ok(Some(Value::Null)) if !column.nullable => errors.push(format!(
+                    "default value of column [{}] cannot be null",
+                    column.name
+                )),
+                Ok(Some(value)) if !value.is_null() && !Self::is_of_type(&value, data_type) => {
+                    errors.push(format!(
+                        "default value of column [{}] should be {:?}",
+                        column.name, data_type
+                    ))
+                }
+                Ok(_) => {}
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        let mut index_names = Vec::new();
+        for index in &table_settings.indexes {
+            Self::validate_index(index, &index_names, &mut errors);
+            index_names.push(index.name.as_str());
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(ConstDBError::InvalidArguments(errors.join("; "))),
+        }
+    }
+
+    fn validate_index(index: &Index, index_names: &[&str], errors: &mut Vec<String>) {
+        if index.name.is_empty()
+            || !index
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            errors.push(format!(
+                "invalid index name [{}], only letters, digits and '_' are allowed",
+                index.name
+            ));
+        } else if index_names.contains(&index.name.as_str()) {
+            errors.push(format!("index [{}] is declared more than once", index.name));
+        }
+        if index.fields.is_empty() {
+            errors.push(format!("index [{}] has no fields", index.name));
+        }
+        for (i, field) in index.fields.iter().enumerate() {
+            if field.data_type.enum_value_or(DataType::Unknown) == DataType::Unknown {
+                errors.push(format!(
+                    "field [{}] of index [{}] has an unknown data type",
+                    field.name, index.name
+                ));
+            }
+            if index.fields[..i].iter().any(|f| f.name == field.name) {
+                errors.push(format!(
+                    "field [{}] of index [{}] is declared more than once",
+                    field.name, index.name
+                ));
+            }
+        }
+    }
+
+    /// build a row from the input data, missing columns are filled with their default values
+    pub fn build_row(
+        &self,
+        mut row: Map<String, Value>,
+    ) -> Result<Map<String, Value>, ConstDBError> {
+        self.validate_row(&mut row)?;
+        Ok(row)
     }
 
-    pub fn update(&self, old: &[u8], patch: &[u8]) -> Result<Bytes, ConstDBError> {
-        let mut old_object = Self::get_json_object(old)?;
-        let patch_object = Self::get_json_object(patch)?;
+    pub fn update(
+        &self,
+        mut old_object: Map<String, Value>,
+        patch_object: Map<String, Value>,
+    ) -> Result<Map<String, Value>, ConstDBError> {
         // updating
         for (k, v) in patch_object {
             old_object.insert(k, v);
         }
+        self.validate_row(&mut old_object)?;
+        Ok(old_object)
+    }
+
+    /// the primary key fields of a row cannot be changed by the patches
+    pub fn check_pk_unchanged(
+        &self,
+        old: &Map<String, Value>,
+        new: &Map<String, Value>,
+    ) -> Result<(), ConstDBError> {
+        for k in &self.table_settings.primary_keys {
+            if old.get(k.name.as_str()) != new.get(k.name.as_str()) {
+                return Err(ConstDBError::InvalidArguments(format!(
+                    "primary key field [{}] cannot be changed",
+                    k.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// validate the fields of a patch to an existing row, which is valid as a whole after the
+    /// patch if the given fields are valid
+    pub fn validate_patch(&self, patch: &Map<String, Value>) -> Result<(), ConstDBError> {
+        self.validate_fields(patch, false)
+    }
+
+    /// encode the patch as the operand of the merge operator of the table, along with the row
+    /// built from it to create if the row does not exist
+    pub fn write_patch(
+        &self,
+        patch: &Map<String, Value>,
+        row: &Map<String, Value>,
+    ) -> Result<Bytes, ConstDBError> {
+        Ok(Bytes::from(encode_row_patch(
+            &self.table_settings,
+            patch,
+            row,
+        )?))
+    }
+
+    /// encode the row to be stored
+    pub fn write_row(
+        &self,
+        row: &Map<String, Value>,
+        revision: u64,
+    ) -> Result<Bytes, ConstDBError> {
+        let row = encode_row(&self.table_settings, row)?;
+        Ok(Bytes::from(encode_revision(revision, &row)))
+    }
+
+    /// the revision of a stored row, which changes on every write of the row
+    pub fn revision(value: &[u8]) -> Result<u64, ConstDBError> {
+        Ok(decode_revision(value)?.0)
+    }
+
+    /// read a stored row as json
+    pub fn row_to_json(&self, value: &[u8]) -> Result<String, ConstDBError> {
+        Ok(serde_json::to_string(&self.read_row(value)?)?)
+    }
+
+    /// read a stored row and resolve it against the current version of the table, the patches
+    /// merged into the row are applied in order
+    pub fn read_row(&self, value: &[u8]) -> Result<Map<String, Value>, ConstDBError> {
+        let (_, value) = decode_revision(value)?;
+        let patched = match decode_patched_row(value)? {
+            Some(patched) => patched,
+            None => return self.read_whole_row(value),
+        };
+        let mut row = match patched.base {
+            Some(base) => self.read_whole_row(base)?,
+            None => Map::new(),
+        };
+        for patch in patched.patches {
+            let (version, data) = decode_row(patch)?;
+            let patch = match data {
+                RowData::Json(json) => Self::get_json_object(json)?,
+                RowData::Binary(_) => {
+                    return Err(ConstDBError::InvalidStates(
+                        "patch of row is not json".to_owned(),
+                    ))
+                }
+            };
+            match version == self.table_settings.version {
+                true => row.extend(patch),
+                false => row.extend(self.resolve_fields(self.settings_of_version(version)?, patch)),
+            }
+        }
+        Ok(row)
+    }
+
+    fn read_whole_row(&self, value: &[u8]) -> Result<Map<String, Value>, ConstDBError> {
+        let (version, data) = decode_row(value)?;
+        let settings = self.settings_of_version(version)?;
+        let row = match data {
+            RowData::Json(json) => Self::get_json_object(json)?,
+            RowData::Binary(message) => decode_binary_row(settings, message)?,
+        };
+        match version == self.table_settings.version {
+            true => Ok(row),
+            false => self.resolve_row(settings, row),
+        }
+    }
+
+    fn settings_of_version(&self, version: u32) -> Result<&TableSettings, ConstDBError> {
+        if version == self.table_settings.version {
+            return Ok(&self.table_settings);
+        }
+        self.history
+            .iter()
+            .find(|s| s.version == version)
+            .ok_or_else(|| {
+                ConstDBError::InvalidStates(format!(
+                    "version {} of table [{}] not found",
+                    version, self.table_settings.name
+                ))
+            })
+    }
+
+    /// the columns of a row written by an older version are matched by the column ids, so renamed
+    /// columns get their new names, dropped columns are removed and new columns get the defaults.
+    fn resolve_row(
+        &self,
+        settings: &TableSettings,
+        row: Map<String, Value>,
+    ) -> Result<Map<String, Value>, ConstDBError> {
+        let mut resolved = self.resolve_fields(settings, row);
+        for column in &self.table_settings.columns {
+            if !resolved.contains_key(column.name.as_str()) {
+                if let Some(value) = Self::default_value(column)? {
+                    resolved.insert(column.name.to_owned(), value);
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// resolve the names of the fields written by an older version, without filling the defaults
+    fn resolve_fields(
+        &self,
+        settings: &TableSettings,
+        row: Map<String, Value>,
+    ) -> Map<String, Value> {
+        let mut resolved = Map::new();
+        for (name, value) in row {
+            match settings.columns.iter().find(|c| c.name == name) {
+                Some(column) => {
+                    if let Some(current) = self
+                        .table_settings
+                        .columns
+                        .iter()
+                        .find(|c| c.id == column.id)
+                    {
+                        resolved.insert(current.name.to_owned(), value);
+                    }
+                }
+                // undeclared fields are kept unless a column of the same name is added later
+                None if !self.table_settings.columns.iter().any(|c| c.name == name) => {
+                    resolved.insert(name, value);
+                }
+                None => {}
+            }
+        }
+        resolved
+    }
+
+    /// validate the row against the declared columns, all the offending fields are reported
+    fn validate_row(&self, row: &mut Map<String, Value>) -> Result<(), ConstDBError> {
+        for column in &self.table_settings.columns {
+            if !row.contains_key(column.name.as_str()) {
+                if let Some(value) = Self::default_value(column)? {
+                    row.insert(column.name.to_owned(), value);
+                }
+            }
+        }
+        self.validate_fields(row, true)
+    }
+
+    /// check the fields against the declared columns, the columns missing from the fields are
+    /// required only if the fields are of a whole row
+    fn validate_fields(
+        &self,
+        fields: &Map<String, Value>,
+        whole_row: bool,
+    ) -> Result<(), ConstDBError> {
+        let mut errors = Vec::new();
+        for column in &self.table_settings.columns {
+            let data_type = column.data_type.enum_value_or(DataType::Unknown);
+            match fields.get(column.name.as_str()) {
+                None if !whole_row => {}
+                None | Some(Value::Null) if column.nullable => {}
+                None => errors.push(format!("field [{}] is required", column.name)),
+                Some(Value::Null) => errors.push(format!("field [{}] cannot be null", column.name)),
+                Some(value) if !Self::is_of_type(value, data_type) => {
+                    errors.push(format!("field [{}] should be {:?}", column.name, data_type))
+                }
+                Some(_) => {}
+            }
+        }
+        if self.table_settings.strict {
+            for name in fields.keys() {
+                if !self.is_declared(name) {
+                    errors.push(format!("field [{}] is not declared", name));
+                }
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(ConstDBError::InvalidArguments(errors.join("; "))),
+        }
+    }
+
+    fn is_declared(&self, name: &str) -> bool {
+        self.table_settings
+            .primary_keys
+            .iter()
+            .any(|k| k.name == name)
+            || self.table_settings.columns.iter().any(|c| c.name == name)
+    }
+
+    fn default_value(column: &Column) -> Result<Option<Value>, ConstDBError> {
+        if column.default_value.is_empty() {
+            return Ok(None);
+        }
+        serde_json::from_str(column.default_value.as_str())
+            .map(Some)
+            .map_err(|e| {
+                ConstDBError::InvalidArguments(format!(
+                    "invalid default value of column [{}]: {}",
+                    column.name, e
+                ))
+            })
+    }
 
-        let bytes = serde_json::to_string(&old_object)?.into_bytes();
-        Ok(Bytes::from(bytes))
+    fn is_of_type(value: &Value, data_type: DataType) -> bool {
+        match (value, data_type) {
+            (Value::String(_), DataType::String) => true,
+            (Value::String(_), DataType::DateTime) => true,
+            (Value::Bool(_), DataType::Boolean) => true,
+            (Value::Number(v), DataType::Int32) => v
+                .as_i64()
+                .map(|i| i >= i32::MIN as i64 && i <= i32::MAX as i64)
+                .unwrap_or(false),
+            (Value::Number(v), DataType::Int64) => v.is_i64(),
+            (Value::Number(v), DataType::Float32) => v.as_f64().and_then(to_f32).is_some(),
+            (Value::Number(_), DataType::Float64) => true,
+            _ => false,
+        }
     }
 
-    fn get_json_object(data: &[u8]) -> Result<Map<String, Value>, ConstDBError> {
+    pub fn get_json_object(data: &[u8]) -> Result<Map<String, Value>, ConstDBError> {
         serde_json::from_slice(data)
             .map_err(ConstDBError::from)
             .and_then(|json| match json {
@@ -39,70 +554,217 @@ impl SchemaHelper {
             })
     }
     /// extract&build primary key from input data
-    pub fn build_pk_from_json(&self, data: &Bytes) -> Result<PrimaryKey, ConstDBError> {
-        let json_object = Self::get_json_object(data)?;
+    pub fn build_pk_from_row(&self, row: &Map<String, Value>) -> Result<PrimaryKey, ConstDBError> {
         let mut pk = Vec::new();
         for k in &self.table_settings.primary_keys {
-            let bytes = SchemaHelper::read_pk_field_from_json(&json_object, k)?;
-            if bytes.is_none() {
+            let value = SchemaHelper::read_pk_field_from_json(row, k)?;
+            if value.is_none() {
                 break;
             }
-            pk.push(bytes.unwrap());
-        }
-
-        let bytes = pk.iter().fold(Vec::new(), |mut r, bytes| {
-            r.extend(bytes);
-            r.push(0);
-            r
-        });
-        match pk.len() < self.table_settings.primary_keys.len() {
-            true => Ok(PrimaryKey::Prefix(bytes)),
-            false => Ok(PrimaryKey::Complete(bytes)),
+            pk.push(value.unwrap());
         }
+        Ok(self.build_pk(&pk))
     }
 
     pub fn build_pk_from_params(
         &self,
         params: &HashMap<String, String>,
     ) -> Result<PrimaryKey, ConstDBError> {
-        let mut pk = Vec::new();
-        for k in &self.table_settings.primary_keys {
-            let bytes = SchemaHelper::read_pk_field_from_params(params, k)?;
-            if bytes.is_none() {
+        Self::build_key_from_params(params, &self.table_settings.primary_keys)
+    }
+
+    /// build the key of the index from the params, the key is always a prefix as more than one row
+    /// can be found by the key.
+    pub fn build_index_key_from_params(
+        index: &Index,
+        params: &HashMap<String, String>,
+    ) -> Result<PrimaryKey, ConstDBError> {
+        match Self::build_key_from_params(params, &index.fields)? {
+            PrimaryKey::Complete(key) => Ok(PrimaryKey::Prefix(key)),
+            key => Ok(key),
+        }
+    }
+
+    /// build the index entry of the row, the entry is the indexed values followed by the primary
+    /// key, or the indexed values only for a unique index. returns `None` if the row misses any of
+    /// the indexed fields.
+    pub fn build_index_key(
+        index: &Index,
+        row: &Map<String, Value>,
+        pk: &[u8],
+    ) -> Result<Option<Vec<u8>>, ConstDBError> {
+        let mut key = Vec::new();
+        for field in &index.fields {
+            let value = match row.get(field.name.as_str()) {
+                None | Some(Value::Null) => return Ok(None),
+                Some(value) => Self::cast_field_data_type(value, field).map_err(|_| {
+                    ConstDBError::InvalidArguments(format!(
+                        "field [{}] of index [{}] should be {:?}",
+                        field.name,
+                        index.name,
+                        field.data_type.enum_value_or(DataType::Unknown)
+                    ))
+                })?,
+            };
+            value.into_iter().for_each(|v| v.encode(&mut key));
+        }
+        if !index.unique {
+            key.extend(pk);
+        }
+        Ok(Some(key))
+    }
+
+    /// the indexed values of the row as a json object
+    pub fn index_values(index: &Index, row: &Map<String, Value>) -> Result<String, ConstDBError> {
+        let values: Map<String, Value> = index
+            .fields
+            .iter()
+            .filter_map(|f| row.get_key_value(f.name.as_str()))
+            .map(|(k, v)| (k.to_owned(), v.clone()))
+            .collect();
+        Ok(serde_json::to_string(&values)?)
+    }
+
+    fn build_key_from_params(
+        params: &HashMap<String, String>,
+        fields: &[Field],
+    ) -> Result<PrimaryKey, ConstDBError> {
+        let mut values = Vec::new();
+        for k in fields {
+            let value = SchemaHelper::read_pk_field_from_params(params, k)?;
+            if value.is_none() {
                 break;
             }
-            pk.push(bytes.unwrap());
+            values.push(value.unwrap());
+        }
+        let key = Self::build_key(fields, &values);
+        let next = fields.get(values.len());
+        Self::build_range_from_params(params, fields, key, next)
+    }
+
+    /// narrow the prefix down with the range predicates (`<field>.gt`, `<field>.gte`,
+    /// `<field>.lt` and `<field>.lte`) on the key field next to the prefix.
+    fn build_range_from_params(
+        params: &HashMap<String, String>,
+        fields: &[Field],
+        key: PrimaryKey,
+        next: Option<&Field>,
+    ) -> Result<PrimaryKey, ConstDBError> {
+        let mut predicates = Vec::new();
+        for (name, value) in params {
+            let (field_name, op) = match name.rsplit_once('.') {
+                Some((field_name, op)) if RANGE_OPERATORS.contains(&op) => (field_name, op),
+                _ => continue,
+            };
+            if !fields.iter().any(|k| k.name == field_name) {
+                continue;
+            }
+            match next {
+                Some(k) if k.name == field_name => {
+                    let value = Self::cast_field_data_type(&Value::String(value.to_string()), k)?;
+                    predicates.push((op, value));
+                }
+                _ => {
+                    return Err(ConstDBError::InvalidArguments(format!(
+                        "range predicate {} is only supported on the field next to the given ones",
+                        name
+                    )))
+                }
+            }
+        }
+
+        let prefix = match key {
+            PrimaryKey::Prefix(prefix) if !predicates.is_empty() => prefix,
+            key => return Ok(key),
+        };
+        let mut lower = prefix.clone();
+        let mut upper = build_upper_bound(&prefix);
+        for (op, value) in predicates {
+            let mut bound = prefix.clone();
+            value.into_iter().for_each(|v| v.encode(&mut bound));
+            match op {
+                "gte" => lower = lower.max(bound),
+                "gt" => match build_upper_bound(&bound) {
+                    Some(after) => lower = lower.max(after),
+                    // nothing sorts after a key of 0xFF bytes only
+                    None => {
+                        return Ok(PrimaryKey::Range {
+                            lower: bound.clone(),
+                            upper: Some(bound),
+                        })
+                    }
+                },
+                "lt" => upper = Some(Self::min_bound(upper, bound)),
+                _ => {
+                    if let Some(after) = build_upper_bound(&bound) {
+                        upper = Some(Self::min_bound(upper, after));
+                    }
+                }
+            }
+        }
+        Ok(PrimaryKey::Range { lower, upper })
+    }
+
+    fn min_bound(upper: Option<Vec<u8>>, bound: Vec<u8>) -> Vec<u8> {
+        match upper {
+            Some(upper) => upper.min(bound),
+            None => bound,
         }
+    }
 
-        let bytes = pk.iter().fold(Vec::new(), |mut r, bytes| {
-            r.extend(bytes);
-            r.push(0);
+    fn build_pk(&self, pk: &[FieldValue]) -> PrimaryKey {
+        Self::build_key(&self.table_settings.primary_keys, pk)
+    }
+
+    fn build_key(fields: &[Field], values: &[FieldValue]) -> PrimaryKey {
+        let bytes = values.iter().fold(Vec::new(), |mut r, value| {
+            value.encode(&mut r);
             r
         });
-        match pk.len() < self.table_settings.primary_keys.len() {
-            true => Ok(PrimaryKey::Prefix(bytes)),
-            false => Ok(PrimaryKey::Complete(bytes)),
+        match values.len() < fields.len() {
+            true => PrimaryKey::Prefix(bytes),
+            false => PrimaryKey::Complete(bytes),
+        }
+    }
+
+    /// decode a stored key back into the values of the primary key fields
+    #[allow(dead_code)]
+    pub fn decode_pk(&self, key: &[u8]) -> Result<Vec<(Field, FieldValue)>, ConstDBError> {
+        let mut pos = 0;
+        let mut values = Vec::new();
+        for k in &self.table_settings.primary_keys {
+            let data_type = k.data_type.enum_value_or(DataType::Unknown);
+            let (value, len) = FieldValue::decode(data_type, &key[pos..])?;
+            values.push((k.clone(), value));
+            pos += len;
         }
+        if pos != key.len() {
+            return Err(ConstDBError::InvalidStates(format!(
+                "{} trailing bytes found in primary key",
+                key.len() - pos
+            )));
+        }
+        Ok(values)
     }
 
     fn read_pk_field_from_params(
         params: &HashMap<String, String>,
         k: &Field,
-    ) -> Result<Option<Vec<u8>>, ConstDBError> {
+    ) -> Result<Option<FieldValue>, ConstDBError> {
         match params.get(k.name.as_str()) {
             Some(s) => Self::cast_field_data_type(&Value::String(s.to_string()), k),
             None => Ok(None),
         }
     }
 
-    fn cast_field_data_type(value: &Value, k: &Field) -> Result<Option<Vec<u8>>, ConstDBError> {
+    fn cast_field_data_type(value: &Value, k: &Field) -> Result<Option<FieldValue>, ConstDBError> {
         match (value, k.data_type.enum_value_or(DataType::Unknown)) {
-            (Value::String(v), DataType::String) => Ok(Some(v.as_bytes().to_vec())),
+            (Value::String(v), DataType::String) => Ok(Some(FieldValue::String(v.to_owned()))),
             (Value::String(v), DataType::Boolean) => {
                 if v.eq_ignore_ascii_case("true") {
-                    Ok(Some(vec![0x01]))
+                    Ok(Some(FieldValue::Boolean(true)))
                 } else if v.eq_ignore_ascii_case("false") {
-                    Ok(Some(vec![0x00]))
+                    Ok(Some(FieldValue::Boolean(false)))
                 } else {
                     Err(ConstDBError::InvalidArguments(format!(
                         "Invalid value for primary key: {}",
@@ -110,7 +772,7 @@ impl SchemaHelper {
                     )))
                 }
             }
-            (Value::String(v), DataType::DateTime) => Ok(Some(v.as_bytes().to_vec())),
+            (Value::String(v), DataType::DateTime) => Ok(Some(FieldValue::DateTime(v.to_owned()))),
             (Value::String(v), DataType::Int32) => {
                 let i = v.parse::<i32>().map_err(|_| {
                     ConstDBError::InvalidArguments(format!(
@@ -118,7 +780,7 @@ impl SchemaHelper {
                         k
                     ))
                 })?;
-                Ok(Some(i.to_be_bytes().to_vec()))
+                Ok(Some(FieldValue::Int32(i)))
             }
             (Value::String(v), DataType::Int64) => {
                 let i = v.parse::<i64>().map_err(|_| {
@@ -127,7 +789,7 @@ impl SchemaHelper {
                         k
                     ))
                 })?;
-                Ok(Some(i.to_be_bytes().to_vec()))
+                Ok(Some(FieldValue::Int64(i)))
             }
             (Value::String(v), DataType::Float32) => {
                 let f = v.parse::<f32>().map_err(|_| {
@@ -136,7 +798,7 @@ impl SchemaHelper {
                         k
                     ))
                 })?;
-                Ok(Some(f.to_be_bytes().to_vec()))
+                Ok(Some(FieldValue::Float32(f)))
             }
             (Value::String(v), DataType::Float64) => {
                 let f = v.parse::<f64>().map_err(|_| {
@@ -145,7 +807,7 @@ impl SchemaHelper {
                         k
                     ))
                 })?;
-                Ok(Some(f.to_be_bytes().to_vec()))
+                Ok(Some(FieldValue::Float64(f)))
             }
             (Value::Number(v), DataType::Int32) => {
                 let num_i64 = v.as_i64().ok_or(ConstDBError::InvalidArguments(format!(
@@ -154,7 +816,7 @@ impl SchemaHelper {
                 )))?;
                 if num_i64 >= i32::MIN as i64 && num_i64 <= i32::MAX as i64 {
                     let num_i32 = num_i64 as i32;
-                    Ok(Some(num_i32.to_be_bytes().to_vec()))
+                    Ok(Some(FieldValue::Int32(num_i32)))
                 } else {
                     Err(ConstDBError::InvalidArguments(format!(
                         "Invalid value for primary key: {}",
@@ -167,16 +829,15 @@ impl SchemaHelper {
                     "Primary key {} cannot be cast to Int64.",
                     k
                 )))?;
-                Ok(Some(i.to_be_bytes().to_vec()))
+                Ok(Some(FieldValue::Int64(i)))
             }
             (Value::Number(v), DataType::Float32) => {
                 let num_f64 = v.as_f64().ok_or(ConstDBError::InvalidArguments(format!(
                     "Primary key {} cannot be cast to Float32.",
                     k
                 )))?;
-                if num_f64 >= f32::MIN as f64 && num_f64 <= f32::MAX as f64 {
-                    let num_f32 = num_f64 as f32;
-                    Ok(Some(num_f32.to_be_bytes().to_vec()))
+                if let Some(num_f32) = to_f32(num_f64) {
+                    Ok(Some(FieldValue::Float32(num_f32)))
                 } else {
                     Err(ConstDBError::InvalidArguments(format!(
                         "Invalid value for primary key: {}",
@@ -189,9 +850,9 @@ impl SchemaHelper {
                     "Primary key {} cannot be cast to Float64.",
                     k
                 )))?;
-                Ok(Some(f.to_be_bytes().to_vec()))
+                Ok(Some(FieldValue::Float64(f)))
             }
-            (Value::Bool(b), DataType::Boolean) => Ok(Some(vec![*b as u8])),
+            (Value::Bool(b), DataType::Boolean) => Ok(Some(FieldValue::Boolean(*b))),
             _ => Err(ConstDBError::InvalidArguments(format!(
                 "unsupported type for primary key {}",
                 k
@@ -202,7 +863,7 @@ impl SchemaHelper {
     fn read_pk_field_from_json(
         json_object: &Map<String, Value>,
         k: &Field,
-    ) -> Result<Option<Vec<u8>>, ConstDBError> {
+    ) -> Result<Option<FieldValue>, ConstDBError> {
         match json_object.get(k.name.as_str()) {
             Some(v) => Self::cast_field_data_type(v, k),
             None => Ok(None),
@@ -255,3 +916,92 @@ impl SchemaHelper {
 //         Ok(())
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patches_are_validated_as_rows() {
+        let mut table = TableSettings::new();
+        table.strict = true;
+        table.primary_keys.push(Field {
+            name: "id".to_owned(),
+            data_type: DataType::Int64.into(),
+            ..Default::default()
+        });
+        table.columns.push(Column {
+            name: "email".to_owned(),
+            data_type: DataType::String.into(),
+            id: 1,
+            ..Default::default()
+        });
+        table.columns.push(Column {
+            name: "age".to_owned(),
+            data_type: DataType::Int32.into(),
+            nullable: true,
+            id: 2,
+            ..Default::default()
+        });
+        let schema = SchemaHelper::new(table);
+        let fields = |value: Value| value.as_object().unwrap().clone();
+
+        assert!(schema
+            .validate_patch(&fields(json!({"id": 1, "age": null})))
+            .is_ok());
+        assert!(schema
+            .build_row(fields(json!({"id": 1, "age": 1})))
+            .is_err());
+        for invalid in [
+            json!({"email": null}),
+            json!({"age": "1"}),
+            json!({"city": "Foo"}),
+        ] {
+            let mut row = fields(json!({"id": 1, "email": "a@b"}));
+            row.extend(fields(invalid.clone()));
+            assert!(
+                schema.build_row(row).is_err(),
+                "{} should be rejected",
+                invalid
+            );
+            assert!(
+                schema.validate_patch(&fields(invalid.clone())).is_err(),
+                "{} should be rejected",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn alterations_change_the_given_options_only() {
+        let mut table = TableSettings::new();
+        table.strict = true;
+        table.version = 1;
+        table.row_history = Some(RowHistory {
+            enabled: true,
+            retention_secs: 60,
+            enabled_at: 1,
+            ..Default::default()
+        })
+        .into();
+        let schema = SchemaHelper::new(table);
+        let alteration = schema
+            .parse_alteration(json!({
+                "options": {"change_feed": true},
+                "row_history": {"retention_secs": 3600}
+            }))
+            .unwrap();
+        let altered = schema.alter(&alteration).unwrap();
+        assert!(altered.strict);
+        assert!(altered.change_feed);
+        assert_eq!(altered.row_history.retention_secs, 3600);
+        assert!(altered.row_history.enabled);
+        assert_eq!(altered.row_history.enabled_at, 1);
+        assert_eq!(altered.version, 2);
+
+        let alteration = schema.parse_alteration(json!({})).unwrap();
+        let altered = schema.alter(&alteration).unwrap();
+        assert!(altered.strict);
+        assert!(!altered.change_feed);
+    }
+}
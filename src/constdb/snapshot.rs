@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::mem::ManuallyDrop;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+use rocksdb::{ReadOptions, SnapshotWithThreadMode};
+use uuid::Uuid;
+
+use super::db::RocksDB;
+use super::errors::ConstDBError;
+use super::Id;
+
+/// a snapshot of the db kept across requests, which owns a reference to the db it borrows
+pub struct DBSnapshot {
+    /// borrows `_rocks_db` for as long as the snapshot lives, it is released in `drop`
+    snapshot: ManuallyDrop<SnapshotWithThreadMode<'static, RocksDB>>,
+    _rocks_db: Arc<RocksDB>,
+}
+
+impl DBSnapshot {
+    fn new(rocks_db: Arc<RocksDB>) -> Self {
+        let snapshot = rocks_db.snapshot();
+        // SAFETY: the snapshot borrows the db on the heap behind the `Arc`, which never moves
+        // while the `Arc` is alive. the snapshot is private and never handed out by value, the
+        // `Arc` is held along with it, and `drop` releases the snapshot before the `Arc`, so
+        // the db outlives every use of the snapshot despite the `'static` lifetime.
+        let snapshot = unsafe {
+            std::mem::transmute::<
+                SnapshotWithThreadMode<'_, RocksDB>,
+                SnapshotWithThreadMode<'static, RocksDB>,
+            >(snapshot)
+        };
+        DBSnapshot {
+            snapshot: ManuallyDrop::new(snapshot),
+            _rocks_db: rocks_db,
+        }
+    }
+
+    /// read at the snapshot, the snapshot should be kept until the reads are done
+    pub fn set_on(&self, read_opts: &mut ReadOptions) {
+        read_opts.set_snapshot(&*self.snapshot);
+    }
+}
+
+impl Drop for DBSnapshot {
+    fn drop(&mut self) {
+        // SAFETY: the snapshot is never used again, and is released while `_rocks_db` still
+        // holds the db it borrows.
+        unsafe { ManuallyDrop::drop(&mut self.snapshot) };
+    }
+}
+
+struct SnapshotEntry {
+    snapshot: Arc<DBSnapshot>,
+    ttl: Duration,
+    last_used: Instant,
+}
+
+/// a snapshot held by a scan of the db for as long as the scan runs, without a token or a ttl. it
+/// is released by the changes of the tables of the db, which need the db to themselves, and the
+/// scan goes on at a new snapshot.
+pub type HeldSnapshot = Arc<HeldSlot>;
+
+/// the snapshot held by a scan, `None` once released
+type HeldSlot = Mutex<Option<Arc<DBSnapshot>>>;
+
+/// the snapshots of a db by their tokens, a snapshot is released once it is not used for its ttl
+#[derive(Default)]
+pub struct Snapshots {
+    entries: Mutex<HashMap<String, SnapshotEntry>>,
+    held: Mutex<Vec<Weak<HeldSlot>>>,
+}
+
+impl Snapshots {
+    /// take a snapshot of the db, returns the token of the snapshot
+    pub fn create(&self, rocks_db: Arc<RocksDB>, ttl: Duration) -> Result<String, ConstDBError> {
+        let token = Uuid::new_v4().simple().to_string();
+        let entry = SnapshotEntry {
+            snapshot: Arc::new(DBSnapshot::new(rocks_db)),
+            ttl,
+            last_used: Instant::now(),
+        };
+        self.lock()?.insert(token.to_owned(), entry);
+        Ok(token)
+    }
+
+    /// the snapshot of the token, which is kept for another ttl
+    pub fn get(&self, token: &str) -> Result<Arc<DBSnapshot>, ConstDBError> {
+        let mut entries = self.lock()?;
+        match entries.get_mut(token) {
+            Some(entry) if entry.last_used.elapsed() < entry.ttl => {
+                entry.last_used = Instant::now();
+                Ok(Arc::clone(&entry.snapshot))
+            }
+            _ => Err(ConstDBError::NotFound(Id::Snapshot(token.to_owned()))),
+        }
+    }
+
+    pub fn release(&self, token: &str) -> Result<(), ConstDBError> {
+        match self.lock()?.remove(token) {
+            Some(_) => Ok(()),
+            None => Err(ConstDBError::NotFound(Id::Snapshot(token.to_owned()))),
+        }
+    }
+
+    /// release the snapshots that are not used for their ttl, returns the number of them
+    pub fn reap(&self) -> Result<usize, ConstDBError> {
+        let mut entries = self.lock()?;
+        let count = entries.len();
+        entries.retain(|_, entry| entry.last_used.elapsed() < entry.ttl);
+        Ok(count - entries.len())
+    }
+
+    /// the number of the snapshots kept
+    pub fn len(&self) -> Result<usize, ConstDBError> {
+        Ok(self.lock()?.len())
+    }
+
+    /// hold the snapshot for a scan, or a new snapshot of the db if not given
+    pub fn hold(
+        &self,
+        rocks_db: Arc<RocksDB>,
+        snapshot: Option<Arc<DBSnapshot>>,
+    ) -> Result<HeldSnapshot, ConstDBError> {
+        let snapshot = snapshot.unwrap_or_else(|| Arc::new(DBSnapshot::new(rocks_db)));
+        let held = Arc::new(Mutex::new(Some(snapshot)));
+        let mut all_held = self.lock_held()?;
+        all_held.retain(|held| held.strong_count() > 0);
+        all_held.push(Arc::downgrade(&held));
+        Ok(held)
+    }
+
+    /// the snapshot held for a scan, a new snapshot of the db is held if it is released
+    pub fn held(
+        &self,
+        held: &HeldSnapshot,
+        rocks_db: &Arc<RocksDB>,
+    ) -> Result<Arc<DBSnapshot>, ConstDBError> {
+        let mut snapshot = held
+            .lock()
+            .map_err(|_| ConstDBError::InvalidStates("snapshot lock poisoned".to_owned()))?;
+        let snapshot =
+            snapshot.get_or_insert_with(|| Arc::new(DBSnapshot::new(Arc::clone(rocks_db))));
+        Ok(Arc::clone(snapshot))
+    }
+
+    /// release the snapshots held for the scans
+    pub fn release_held(&self) -> Result<(), ConstDBError> {
+        let mut all_held = self.lock_held()?;
+        for held in all_held.iter().filter_map(|held| held.upgrade()) {
+            *held
+                .lock()
+                .map_err(|_| ConstDBError::InvalidStates("snapshot lock poisoned".to_owned()))? =
+                None;
+        }
+        all_held.retain(|held| held.strong_count() > 0);
+        Ok(())
+    }
+
+    fn lock_held(&self) -> Result<MutexGuard<'_, Vec<Weak<HeldSlot>>>, ConstDBError> {
+        self.held
+            .lock()
+            .map_err(|_| ConstDBError::InvalidStates("snapshots lock poisoned".to_owned()))
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, HashMap<String, SnapshotEntry>>, ConstDBError> {
+        self.entries
+            .lock()
+            .map_err(|_| ConstDBError::InvalidStates("snapshots lock poisoned".to_owned()))
+    }
+}
@@ -1,10 +1,35 @@
 use super::errors::ConstDBError;
 
 pub enum SystemKeys {
-    TableMetaKey { db: String, table: String },
-    TableMetaPrefix { db: String },
-    DBMetaKey { db: String },
+    TableMetaKey {
+        db: String,
+        table: String,
+    },
+    TableMetaPrefix {
+        db: String,
+    },
+    TableVersionKey {
+        db: String,
+        table: String,
+        version: u32,
+    },
+    TableVersionPrefix {
+        db: String,
+        table: String,
+    },
+    /// the versions of all the tables of the db
+    DBTableVersionPrefix {
+        db: String,
+    },
+    DBMetaKey {
+        db: String,
+    },
     DBMetaPrefix,
+    /// the progress of re-keying a table, the last key of the table re-keyed
+    TableMigrationKey {
+        db: String,
+        table: String,
+    },
 }
 
 impl SystemKeys {
@@ -19,10 +44,36 @@ impl SystemKeys {
         SystemKeys::TableMetaPrefix { db: db.to_owned() }
     }
 
+    pub fn table_version_key(db: &str, table: &str, version: u32) -> Self {
+        SystemKeys::TableVersionKey {
+            db: db.to_owned(),
+            table: table.to_owned(),
+            version,
+        }
+    }
+
+    pub fn table_version_prefix(db: &str, table: &str) -> Self {
+        SystemKeys::TableVersionPrefix {
+            db: db.to_owned(),
+            table: table.to_owned(),
+        }
+    }
+
+    pub fn db_table_version_prefix(db: &str) -> Self {
+        SystemKeys::DBTableVersionPrefix { db: db.to_owned() }
+    }
+
     pub fn db_meta_key(db: &str) -> Self {
         SystemKeys::DBMetaKey { db: db.to_owned() }
     }
 
+    pub fn table_migration_key(db: &str, table: &str) -> Self {
+        SystemKeys::TableMigrationKey {
+            db: db.to_owned(),
+            table: table.to_owned(),
+        }
+    }
+
     pub fn parse_table_meta_key(bytes: &[u8]) -> Result<(String, String), ConstDBError> {
         match String::from_utf8(bytes.to_vec()) {
             Ok(s) if s.starts_with('t') && s.contains('.') => {
@@ -58,8 +109,14 @@ impl SystemKeys {
         match self {
             SystemKeys::TableMetaKey { db, table } => format!("t{}.{}", db, table),
             SystemKeys::TableMetaPrefix { db } => format!("t{}.", db),
+            SystemKeys::TableVersionKey { db, table, version } => {
+                format!("v{}.{}.{:010}", db, table, version)
+            }
+            SystemKeys::TableVersionPrefix { db, table } => format!("v{}.{}.", db, table),
+            SystemKeys::DBTableVersionPrefix { db } => format!("v{}.", db),
             SystemKeys::DBMetaKey { db } => format!("d{}", db),
             SystemKeys::DBMetaPrefix => "d".to_owned(),
+            SystemKeys::TableMigrationKey { db, table } => format!("m{}.{}", db, table),
         }
     }
 }
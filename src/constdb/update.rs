@@ -0,0 +1,233 @@
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+
+use super::errors::ConstDBError;
+use crate::protos::constdb_model::{DataType, TableSettings};
+
+/// an update of a row by operators, applied to the stored row within the engine
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RowUpdate {
+    #[serde(rename = "$set", default)]
+    set: Map<String, Value>,
+    /// the fields are set only if the row is created by the update
+    #[serde(rename = "$setOnInsert", default)]
+    set_on_insert: Map<String, Value>,
+    /// add the numbers to the fields, a missing field is taken as 0
+    #[serde(rename = "$inc", default)]
+    inc: Map<String, Value>,
+    /// append the value to the array of the field, or all the values of `{"$each": [...]}`
+    #[serde(rename = "$push", default)]
+    push: Map<String, Value>,
+    /// remove the fields, the values are ignored
+    #[serde(rename = "$unset", default)]
+    unset: Map<String, Value>,
+}
+
+impl RowUpdate {
+    pub fn parse(data: &[u8]) -> Result<Self, ConstDBError> {
+        serde_json::from_slice(data)
+            .map_err(|e| ConstDBError::InvalidArguments(format!("invalid update: {}", e)))
+    }
+
+    /// validate the operators against the primary keys and the columns of the table, a field can
+    /// be updated by one operator only
+    pub fn validate(&self, table: &TableSettings) -> Result<(), ConstDBError> {
+        let mut errors = Vec::new();
+        let operators = [
+            ("$set", &self.set),
+            ("$setOnInsert", &self.set_on_insert),
+            ("$inc", &self.inc),
+            ("$push", &self.push),
+            ("$unset", &self.unset),
+        ];
+        for (i, (operator, fields)) in operators.iter().enumerate() {
+            for name in fields.keys() {
+                if table.primary_keys.iter().any(|k| &k.name == name) {
+                    errors.push(format!("primary key field [{}] cannot be updated", name));
+                }
+                if operators[..i].iter().any(|(_, f)| f.contains_key(name)) {
+                    errors.push(format!(
+                        "field [{}] is updated by more than one operator",
+                        name
+                    ));
+                }
+                let data_type = table
+                    .columns
+                    .iter()
+                    .find(|c| &c.name == name)
+                    .map(|c| c.data_type.enum_value_or(DataType::Unknown));
+                match (*operator, data_type) {
+                    ("$inc", Some(DataType::String | DataType::DateTime | DataType::Boolean)) => {
+                        errors.push(format!("$inc of field [{}] is not a number", name))
+                    }
+                    ("$inc", _) if !fields[name].is_number() => {
+                        errors.push(format!("$inc of field [{}] should be a number", name))
+                    }
+                    ("$push", Some(_)) => {
+                        errors.push(format!("$push of field [{}] is not an array column", name))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(ConstDBError::InvalidArguments(errors.join("; "))),
+        }
+    }
+
+    /// apply the operators to the existing row, or to the primary key fields if the row does
+    /// not exist
+    pub fn apply(
+        &self,
+        existing: Option<Map<String, Value>>,
+        pk_fields: Map<String, Value>,
+    ) -> Result<Map<String, Value>, ConstDBError> {
+        let inserting = existing.is_none();
+        let mut row = existing.unwrap_or(pk_fields);
+        for (name, value) in &self.set {
+            row.insert(name.to_owned(), value.clone());
+        }
+        if inserting {
+            for (name, value) in &self.set_on_insert {
+                row.insert(name.to_owned(), value.clone());
+            }
+        }
+        for (name, value) in &self.inc {
+            let sum = match row.get(name) {
+                None | Some(Value::Null) => value.clone(),
+                Some(Value::Number(current)) => Value::Number(Self::add(name, current, value)?),
+                Some(_) => {
+                    return Err(ConstDBError::InvalidArguments(format!(
+                        "cannot $inc field [{}] that is not a number",
+                        name
+                    )))
+                }
+            };
+            row.insert(name.to_owned(), sum);
+        }
+        for (name, value) in &self.push {
+            let values = match value.get("$each") {
+                Some(Value::Array(values)) => values.clone(),
+                Some(_) => {
+                    return Err(ConstDBError::InvalidArguments(format!(
+                        "$each of field [{}] should be an array",
+                        name
+                    )))
+                }
+                None => vec![value.clone()],
+            };
+            match row.get_mut(name) {
+                None | Some(Value::Null) => {
+                    row.insert(name.to_owned(), Value::Array(values));
+                }
+                Some(Value::Array(array)) => array.extend(values),
+                Some(_) => {
+                    return Err(ConstDBError::InvalidArguments(format!(
+                        "cannot $push to field [{}] that is not an array",
+                        name
+                    )))
+                }
+            }
+        }
+        for name in self.unset.keys() {
+            row.remove(name);
+        }
+        Ok(row)
+    }
+
+    /// integers are added as integers, and fail on overflow
+    fn add(name: &str, current: &Number, value: &Value) -> Result<Number, ConstDBError> {
+        let overflow =
+            || ConstDBError::InvalidArguments(format!("$inc of field [{}] overflows", name));
+        match (current.as_i64(), value.as_i64()) {
+            (Some(current), Some(value)) => current
+                .checked_add(value)
+                .map(Number::from)
+                .ok_or_else(overflow),
+            _ => {
+                let sum = current.as_f64().unwrap_or_default() + value.as_f64().unwrap_or_default();
+                Number::from_f64(sum).ok_or_else(overflow)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn update(value: Value) -> RowUpdate {
+        RowUpdate::parse(value.to_string().as_bytes()).unwrap()
+    }
+
+    fn row(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn operators_update_an_existing_row() {
+        let update = update(json!({
+            "$set": {"name": "b"},
+            "$setOnInsert": {"since": "2024-01-01"},
+            "$inc": {"visits": 1, "score": 0.5, "new": 2},
+            "$push": {"tags": "c", "list": {"$each": [1, 2]}},
+            "$unset": {"address": ""}
+        }));
+        let existing = row(json!({
+            "id": 1,
+            "name": "a",
+            "visits": 9,
+            "score": 1,
+            "tags": ["a", "b"],
+            "address": "Foo"
+        }));
+        let updated = update.apply(Some(existing), row(json!({"id": 1}))).unwrap();
+        assert_eq!(
+            Value::Object(updated),
+            json!({
+                "id": 1,
+                "name": "b",
+                "visits": 10,
+                "score": 1.5,
+                "new": 2,
+                "tags": ["a", "b", "c"],
+                "list": [1, 2]
+            })
+        );
+    }
+
+    #[test]
+    fn set_on_insert_applies_to_new_rows_only() {
+        let update =
+            update(json!({"$setOnInsert": {"since": "2024-01-01"}, "$inc": {"visits": 1}}));
+        let inserted = update.apply(None, row(json!({"id": 1}))).unwrap();
+        assert_eq!(
+            Value::Object(inserted),
+            json!({"id": 1, "since": "2024-01-01", "visits": 1})
+        );
+    }
+
+    #[test]
+    fn invalid_targets_are_rejected() {
+        let existing = || Some(row(json!({"name": "a", "visits": i64::MAX})));
+        let invalid = [
+            json!({"$inc": {"name": 1}}),
+            json!({"$inc": {"visits": 1}}),
+            json!({"$push": {"name": "b"}}),
+            json!({"$push": {"tags": {"$each": "b"}}}),
+        ];
+        for operators in invalid {
+            assert!(
+                update(operators.clone())
+                    .apply(existing(), Map::new())
+                    .is_err(),
+                "{} should be rejected",
+                operators
+            );
+        }
+        assert!(RowUpdate::parse(br#"{"$rename": {"a": "b"}}"#).is_err());
+    }
+}
@@ -0,0 +1,484 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use rocksdb::Transaction;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::changes::{Change, ChangeOp};
+use super::db::{DBInstance, RocksDB};
+use super::errors::ConstDBError;
+use super::history::{now_millis, version_key};
+use super::patch::RowPatch;
+use super::schema::SchemaHelper;
+use super::update::RowUpdate;
+use super::Id;
+
+/// how `upsert` writes over an existing row
+#[derive(Clone, Copy)]
+pub enum UpsertMode {
+    /// the fields of the data are merged into the existing row
+    Merge,
+    /// the existing row is replaced by the data
+    Replace,
+}
+
+impl UpsertMode {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ConstDBError> {
+        match params.get("mode").map(|mode| mode.as_str()) {
+            None | Some("merge") => Ok(UpsertMode::Merge),
+            Some("replace") => Ok(UpsertMode::Replace),
+            Some(mode) => Err(ConstDBError::InvalidArguments(format!(
+                "invalid mode: {}, should be either merge or replace",
+                mode
+            ))),
+        }
+    }
+}
+
+/// an operation of a batch write or a transaction
+#[derive(Deserialize)]
+pub struct Operation {
+    /// the table of the operation, required by the operations of a transaction only
+    pub table: Option<String>,
+    pub op: OperationKind,
+    /// the row to write, or the primary key fields of the row to delete
+    pub row: Map<String, Value>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationKind {
+    Insert,
+    Upsert,
+    Replace,
+    Delete,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteResult {
+    Created,
+    Updated,
+    Deleted,
+    NotFound,
+}
+
+/// the conditions of `If-Match` and `If-None-Match` on the entity tag of the row to write
+#[derive(Default)]
+pub struct Precondition {
+    if_match: Option<Vec<String>>,
+    if_none_match: Option<Vec<String>>,
+}
+
+impl Precondition {
+    pub fn new(if_match: Option<&str>, if_none_match: Option<&str>) -> Self {
+        Precondition {
+            if_match: if_match.map(Self::parse_tags),
+            if_none_match: if_none_match.map(Self::parse_tags),
+        }
+    }
+
+    /// the entity tag of a revision of the row
+    pub fn entity_tag(revision: u64) -> String {
+        format!("\"{}\"", revision)
+    }
+
+    /// the weak tags are compared as the strong ones, as the rows have no weak tags
+    fn parse_tags(header: &str) -> Vec<String> {
+        header
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/").to_owned())
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.if_match.is_none() && self.if_none_match.is_none()
+    }
+
+    /// check the entity tag of the current row, which is `None` if the row does not exist
+    fn check(&self, entity_tag: Option<&str>) -> Result<(), ConstDBError> {
+        let matches = |tags: &Vec<String>| match entity_tag {
+            Some(entity_tag) => tags.iter().any(|tag| tag == "*" || tag == entity_tag),
+            None => false,
+        };
+        if let Some(tags) = &self.if_match {
+            if !matches(tags) {
+                return Err(ConstDBError::PreconditionFailed(
+                    "the row does not match If-Match".to_owned(),
+                ));
+            }
+        }
+        if let Some(tags) = &self.if_none_match {
+            if matches(tags) {
+                return Err(ConstDBError::PreconditionFailed(
+                    "the row matches If-None-Match".to_owned(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// write the row only if the current row is as expected
+#[derive(Deserialize)]
+pub struct CompareAndSwap {
+    /// the expected values of the fields of the current row, a missing field is taken as null.
+    /// `null` expects the row not to exist.
+    pub expected: Value,
+    /// the row to write, which replaces the current row
+    pub row: Map<String, Value>,
+}
+
+impl CompareAndSwap {
+    pub fn parse(data: &[u8]) -> Result<Self, ConstDBError> {
+        let cas: CompareAndSwap = serde_json::from_slice(data).map_err(|e| {
+            ConstDBError::InvalidArguments(format!("invalid compare and swap: {}", e))
+        })?;
+        match cas.expected {
+            Value::Null | Value::Object(_) => Ok(cas),
+            _ => Err(ConstDBError::InvalidArguments(
+                "expected should be either a json object or null".to_owned(),
+            )),
+        }
+    }
+
+    fn matches(&self, current: Option<&Map<String, Value>>) -> bool {
+        match (&self.expected, current) {
+            (Value::Null, current) => current.is_none(),
+            (Value::Object(expected), Some(current)) => expected
+                .iter()
+                .all(|(name, value)| current.get(name).unwrap_or(&Value::Null) == value),
+            _ => false,
+        }
+    }
+}
+
+impl Operation {
+    /// parse the operations from a json array, or from json objects one per line
+    pub fn parse_all(data: &[u8]) -> Result<Vec<Operation>, ConstDBError> {
+        let invalid = |e: serde_json::Error| {
+            ConstDBError::InvalidArguments(format!("invalid batch operations: {}", e))
+        };
+        if data.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'[') {
+            return serde_json::from_slice(data).map_err(invalid);
+        }
+        data.split(|b| *b == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .map(|line| serde_json::from_slice(line).map_err(invalid))
+            .collect()
+    }
+}
+
+/// the writes of rows in an optimistic transaction along with their index entries. the existing
+/// rows and the entries of the unique indexes are read for update, so the commit fails with
+/// `ConstDBError::Conflict` if any of them is written by others in the meantime. the later writes
+/// of the transaction see the rows written by the earlier ones.
+pub struct RowWrites<'a> {
+    db: &'a DBInstance,
+    txn: Transaction<'a, RocksDB>,
+    /// the changes of the rows of the tables with change feeds, logged on commit
+    changes: RefCell<Vec<Change>>,
+}
+
+impl<'a> RowWrites<'a> {
+    pub fn new(db: &'a DBInstance) -> Result<Self, ConstDBError> {
+        Ok(RowWrites {
+            db,
+            txn: db.rocks_db()?.transaction(),
+            changes: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// write the operation of a batch or a transaction
+    pub fn apply(
+        &self,
+        schema: &SchemaHelper,
+        operation: Operation,
+    ) -> Result<WriteResult, ConstDBError> {
+        match operation.op {
+            OperationKind::Insert => self.insert(schema, operation.row),
+            OperationKind::Upsert => self.upsert(schema, operation.row, UpsertMode::Merge),
+            OperationKind::Replace => self.upsert(schema, operation.row, UpsertMode::Replace),
+            OperationKind::Delete => {
+                let pk = schema.build_pk_from_row(&operation.row)?;
+                self.delete(schema, pk.complete()?)
+            }
+        }
+    }
+
+    /// write a new row, fails if a row of the same primary key exists
+    pub fn insert(
+        &self,
+        schema: &SchemaHelper,
+        row: Map<String, Value>,
+    ) -> Result<WriteResult, ConstDBError> {
+        let pk = schema.build_pk_from_row(&row)?;
+        let pk = pk.complete()?;
+        let row = schema.build_row(row)?;
+        let table = self.db.rocks_db_for_table(Self::table_name(schema))?;
+        if self
+            .txn
+            .get_pinned_for_update_cf(table, pk, true)?
+            .is_some()
+        {
+            return Err(ConstDBError::AlreadyExists(Id::Data));
+        }
+        self.write(schema, pk, None, Some(row))?;
+        Ok(WriteResult::Created)
+    }
+
+    pub fn upsert(
+        &self,
+        schema: &SchemaHelper,
+        row: Map<String, Value>,
+        mode: UpsertMode,
+    ) -> Result<WriteResult, ConstDBError> {
+        let pk = schema.build_pk_from_row(&row)?;
+        let pk = pk.complete()?;
+        // the rows of the tables without indexes, row history or change feed are patched by the
+        // merge operator without being read, so the concurrent upserts of a row are all applied
+        // instead of conflicting with each other. the patch carries the row built from its fields,
+        // which is created if the row does not exist, so the fields should make a valid row on
+        // their own, otherwise the row is read to be patched. the row is reported as updated, as
+        // it is not known to exist.
+        if matches!(mode, UpsertMode::Merge)
+            && schema.table_settings().indexes.is_empty()
+            && schema.row_history().is_none()
+            && !schema.table_settings().change_feed
+        {
+            // the fields are refused before they are written, an operand is never merged with
+            // fields that would make the row invalid
+            schema.validate_patch(&row)?;
+            if let Ok(whole_row) = schema.build_row(row.clone()) {
+                let table = self.db.rocks_db_for_table(Self::table_name(schema))?;
+                self.txn
+                    .merge_cf(table, pk, schema.write_patch(&row, &whole_row)?)?;
+                return Ok(WriteResult::Updated);
+            }
+        }
+        match self.current(schema, pk)? {
+            Some(existing) => {
+                let row = match mode {
+                    UpsertMode::Merge => schema.update(existing.clone(), row)?,
+                    UpsertMode::Replace => schema.build_row(row)?,
+                };
+                self.write(schema, pk, Some(existing), Some(row))?;
+                Ok(WriteResult::Updated)
+            }
+            None => {
+                let row = schema.build_row(row)?;
+                self.write(schema, pk, None, Some(row))?;
+                Ok(WriteResult::Created)
+            }
+        }
+    }
+
+    /// patch the existing row, the primary key fields of the row cannot be changed by the patch
+    pub fn patch(
+        &self,
+        schema: &SchemaHelper,
+        pk: &[u8],
+        patch: &RowPatch,
+    ) -> Result<WriteResult, ConstDBError> {
+        let existing = self
+            .current(schema, pk)?
+            .ok_or(ConstDBError::NotFound(Id::Data))?;
+        let row = patch.apply(existing.clone())?;
+        schema.check_pk_unchanged(&existing, &row)?;
+        let row = schema.build_row(row)?;
+        self.write(schema, pk, Some(existing), Some(row))?;
+        Ok(WriteResult::Updated)
+    }
+
+    /// update the row by the operators, the row of the primary key fields is created if it does
+    /// not exist. returns the updated row.
+    pub fn update(
+        &self,
+        schema: &SchemaHelper,
+        pk: &[u8],
+        pk_fields: Map<String, Value>,
+        update: &RowUpdate,
+    ) -> Result<Map<String, Value>, ConstDBError> {
+        let existing = self.current(schema, pk)?;
+        let row = schema.build_row(update.apply(existing.clone(), pk_fields)?)?;
+        self.write(schema, pk, existing, Some(row.clone()))?;
+        Ok(row)
+    }
+
+    /// write the row if the current row is as expected, returns whether the row is written along
+    /// with the row after all, which is `None` if the row does not exist
+    pub fn compare_and_swap(
+        &self,
+        schema: &SchemaHelper,
+        cas: &CompareAndSwap,
+    ) -> Result<(bool, Option<Map<String, Value>>), ConstDBError> {
+        let pk = schema.build_pk_from_row(&cas.row)?;
+        let pk = pk.complete()?;
+        let existing = self.current(schema, pk)?;
+        if !cas.matches(existing.as_ref()) {
+            return Ok((false, existing));
+        }
+        let row = schema.build_row(cas.row.clone())?;
+        self.write(schema, pk, existing, Some(row.clone()))?;
+        Ok((true, Some(row)))
+    }
+
+    pub fn delete(&self, schema: &SchemaHelper, pk: &[u8]) -> Result<WriteResult, ConstDBError> {
+        match self.current(schema, pk)? {
+            Some(existing) => {
+                self.write(schema, pk, Some(existing), None)?;
+                Ok(WriteResult::Deleted)
+            }
+            None => Ok(WriteResult::NotFound),
+        }
+    }
+
+    /// check the precondition against the revision of the row, the row is read for update so
+    /// that the write conflicts with the concurrent writes of the row
+    pub fn check(
+        &self,
+        schema: &SchemaHelper,
+        pk: &[u8],
+        precondition: &Precondition,
+    ) -> Result<(), ConstDBError> {
+        if precondition.is_empty() {
+            return Ok(());
+        }
+        let table = self.db.rocks_db_for_table(Self::table_name(schema))?;
+        let entity_tag = match self.txn.get_pinned_for_update_cf(table, pk, true)? {
+            Some(value) => Some(Precondition::entity_tag(SchemaHelper::revision(&value)?)),
+            None => None,
+        };
+        precondition.check(entity_tag.as_deref())
+    }
+
+    pub fn commit(self) -> Result<(), ConstDBError> {
+        let changes = self.changes.into_inner();
+        if changes.is_empty() {
+            self.txn.commit()?;
+            return Ok(());
+        }
+        let cf = self
+            .db
+            .rocks_db_for_changes()?
+            .ok_or_else(|| ConstDBError::InvalidStates("cannot find change log".to_owned()))?;
+        self.db.changes.commit(self.txn, cf, changes)
+    }
+
+    /// the current row of the primary key, read for update
+    fn current(
+        &self,
+        schema: &SchemaHelper,
+        pk: &[u8],
+    ) -> Result<Option<Map<String, Value>>, ConstDBError> {
+        let table = self.db.rocks_db_for_table(Self::table_name(schema))?;
+        self.txn
+            .get_for_update_cf(table, pk, true)?
+            .map(|v| schema.read_row(&v))
+            .transpose()
+    }
+
+    /// write the row, or delete the row if `row` is `None`, the index entries of the `existing`
+    /// row are replaced by the ones of the new row, and the stored row is kept in the row history
+    /// if the table has one. the change is logged if the table has a change feed.
+    fn write(
+        &self,
+        schema: &SchemaHelper,
+        pk: &[u8],
+        existing: Option<Map<String, Value>>,
+        row: Option<Map<String, Value>>,
+    ) -> Result<(), ConstDBError> {
+        let table_name = Self::table_name(schema);
+        for index in &schema.table_settings().indexes {
+            let index_cf = self
+                .db
+                .rocks_db_for_index(table_name, index.name.as_str())?;
+            if let Some(existing) = &existing {
+                if let Some(key) = SchemaHelper::build_index_key(index, existing, pk)? {
+                    self.txn.delete_cf(index_cf, key)?;
+                }
+            }
+            if let Some(row) = &row {
+                if let Some(key) = SchemaHelper::build_index_key(index, row, pk)? {
+                    if index.unique {
+                        match self.txn.get_for_update_cf(index_cf, &key, true)? {
+                            Some(owner) if owner.as_slice() != pk => {
+                                return Err(ConstDBError::AlreadyExists(Id::IndexEntry {
+                                    index: index.name.to_owned(),
+                                    values: SchemaHelper::index_values(index, row)?,
+                                }))
+                            }
+                            _ => {}
+                        }
+                    }
+                    self.txn.put_cf(index_cf, key, pk)?;
+                }
+            }
+        }
+        let table = self.db.rocks_db_for_table(table_name)?;
+        let previous = self.txn.get_pinned_cf(table, pk)?;
+        if schema.row_history().is_some() {
+            let history = self.db.rocks_db_for_row_history(table_name)?;
+            let key = version_key(pk, now_millis());
+            // of the writes of a row within the same millisecond, the row before the first one is
+            // kept, which is the version of the row as of the millisecond before
+            if self.txn.get_for_update_cf(history, &key, true)?.is_none() {
+                self.txn
+                    .put_cf(history, key, previous.as_deref().unwrap_or_default())?;
+            }
+        }
+        match &row {
+            Some(row) => {
+                let revision = match previous {
+                    Some(value) => SchemaHelper::revision(&value)? + 1,
+                    // every write takes a sequence number of the db, so a revision never exceeds
+                    // the latest sequence number. a new row starts from the next one, so that a
+                    // row deleted and written again never gets an old revision back.
+                    None => self.db.rocks_db()?.latest_sequence_number() + 1,
+                };
+                self.txn
+                    .put_cf(table, pk, schema.write_row(row, revision)?)?
+            }
+            None => self.txn.delete_cf(table, pk)?,
+        }
+        if schema.table_settings().change_feed {
+            self.changes
+                .borrow_mut()
+                .push(Self::change(schema, pk, existing, row)?);
+        }
+        Ok(())
+    }
+
+    /// the change of the row, which is numbered on commit
+    fn change(
+        schema: &SchemaHelper,
+        pk: &[u8],
+        before: Option<Map<String, Value>>,
+        after: Option<Map<String, Value>>,
+    ) -> Result<Change, ConstDBError> {
+        let op = match (&before, &after) {
+            (None, _) => ChangeOp::Insert,
+            (Some(_), Some(_)) => ChangeOp::Update,
+            (Some(_), None) => ChangeOp::Delete,
+        };
+        Ok(Change {
+            seq: 0,
+            ts: 0,
+            table: Self::table_name(schema).to_owned(),
+            op,
+            key: schema
+                .decode_pk(pk)?
+                .into_iter()
+                .map(|(k, v)| (k.name, v.to_json()))
+                .collect(),
+            before,
+            after,
+        })
+    }
+
+    fn table_name(schema: &SchemaHelper) -> &str {
+        schema.table_settings().name.as_str()
+    }
+}
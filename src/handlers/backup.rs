@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::constdb::errors::ConstDBError;
+use crate::constdb::Engine;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use tokio::sync::RwLock;
+
+pub fn backup_routes() -> Router<Arc<RwLock<Engine>>> {
+    Router::new()
+        .route("/", post(create_backup_route))
+        .route("/", get(list_backups_route))
+        .route("/purge", post(purge_backups_route))
+        .route("/:backup_id/restore", post(restore_db_route))
+}
+
+pub async fn create_backup_route(State(const_db): State<Arc<RwLock<Engine>>>) -> impl IntoResponse {
+    let cdb = const_db.read().await;
+    let result = cdb.create_backup();
+    match result {
+        Ok(backup) => (StatusCode::CREATED, Json(backup)).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
+
+pub async fn list_backups_route(State(const_db): State<Arc<RwLock<Engine>>>) -> impl IntoResponse {
+    let cdb = const_db.read().await;
+    let result = cdb.list_backups();
+    match result {
+        Ok(backups) => (StatusCode::OK, Json(backups)).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
+
+/// purge the old backups, and keep the latest `keep` ones
+pub async fn purge_backups_route(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let keep = match params.get("keep").map(|keep| keep.parse::<usize>()) {
+        Some(Ok(keep)) => keep,
+        _ => {
+            let e = ConstDBError::InvalidArguments(
+                "keep should be the number of the latest backups to keep".to_owned(),
+            );
+            return (e.http_status_code(), e.to_string()).into_response();
+        }
+    };
+    let cdb = const_db.read().await;
+    let result = cdb.purge_backups(keep);
+    match result {
+        Ok(backups) => (StatusCode::OK, Json(backups)).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
+
+/// restore the database `db` from the backup
+pub async fn restore_db_route(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path(backup_id): Path<u32>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let db_name = match params.get("db") {
+        Some(db_name) => db_name,
+        None => {
+            let e = ConstDBError::InvalidArguments("db to restore is missing".to_owned());
+            return (e.http_status_code(), e.to_string()).into_response();
+        }
+    };
+    println!("restoring db [{}] from backup {}...", db_name, backup_id);
+    let mut cdb = const_db.write().await;
+    let result = cdb.restore_db(Some(backup_id), db_name.as_str());
+    match result {
+        Ok(backup) => (StatusCode::OK, Json(backup)).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::constdb::Engine;
+
+use axum::body::{self, Body, Bytes};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tokio::sync::RwLock;
+
+/// changes are read from the change log in batches of this size
+const CHANGE_BATCH_SIZE: usize = 1000;
+/// a heartbeat is sent at this interval while there are no changes, so that the stream ends once
+/// the client is gone
+const CHANGE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Copy)]
+enum ChangeFormat {
+    /// a json change per line
+    NdJson,
+    /// server-sent events with the sequence numbers of the changes as the ids
+    EventStream,
+}
+
+impl ChangeFormat {
+    fn from_request(params: &HashMap<String, String>, headers: &HeaderMap) -> Self {
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        match params.get("format").map(|f| f.as_str()) {
+            Some("sse") => ChangeFormat::EventStream,
+            None if accept.contains("text/event-stream") => ChangeFormat::EventStream,
+            _ => ChangeFormat::NdJson,
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ChangeFormat::NdJson => "application/x-ndjson",
+            ChangeFormat::EventStream => "text/event-stream",
+        }
+    }
+
+    fn heartbeat(&self) -> &'static str {
+        match self {
+            ChangeFormat::NdJson => "\n",
+            ChangeFormat::EventStream => ":\n\n",
+        }
+    }
+}
+
+pub async fn db_changes_route(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path(db_name): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    stream_changes(const_db, db_name, None, params, headers).await
+}
+
+pub async fn table_changes_route(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path((db_name, table_name)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    stream_changes(const_db, db_name, Some(table_name), params, headers).await
+}
+
+/// stream the changes after `since`, and follow the new changes until the client is gone
+async fn stream_changes(
+    const_db: Arc<RwLock<Engine>>,
+    db_name: String,
+    table_name: Option<String>,
+    params: HashMap<String, String>,
+    headers: HeaderMap,
+) -> Response {
+    let format = ChangeFormat::from_request(&params, &headers);
+    // an event stream is resumed by the id of the last event received
+    let since = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| params.get("since").map(|s| s.as_str()));
+    let subscription =
+        const_db
+            .read()
+            .await
+            .subscribe_changes(db_name.as_str(), table_name.as_deref(), since);
+    let (mut since, mut receiver) = match subscription {
+        Ok(subscription) => subscription,
+        Err(e) => return (e.http_status_code(), e.to_string()).into_response(),
+    };
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            // marked as seen before reading, so that the changes committed meanwhile wake it up
+            receiver.borrow_and_update();
+            let result = const_db.read().await.read_changes(
+                db_name.as_str(),
+                table_name.as_deref(),
+                since,
+                CHANGE_BATCH_SIZE,
+            );
+            let chunk = result.and_then(|(changes, last_seq)| {
+                let mut chunk = String::new();
+                for change in &changes {
+                    let json = serde_json::to_string(change)?;
+                    match format {
+                        ChangeFormat::NdJson => {
+                            chunk.push_str(json.as_str());
+                            chunk.push('\n');
+                        }
+                        ChangeFormat::EventStream => chunk
+                            .push_str(format!("id: {}\ndata: {}\n\n", change.seq, json).as_str()),
+                    }
+                }
+                Ok((chunk, last_seq))
+            });
+            let (chunk, last_seq) = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    println!("change feed of db [{}] failed: {}", db_name, e.to_string());
+                    sender.abort();
+                    return;
+                }
+            };
+            if !chunk.is_empty() && sender.send_data(Bytes::from(chunk)).await.is_err() {
+                return;
+            }
+            let caught_up = last_seq == since;
+            since = last_seq;
+            if !caught_up {
+                continue;
+            }
+            match tokio::time::timeout(CHANGE_HEARTBEAT_INTERVAL, receiver.changed()).await {
+                Ok(Ok(())) => {}
+                // the db is dropped
+                Ok(Err(_)) => return,
+                Err(_) => {
+                    let heartbeat = Bytes::from_static(format.heartbeat().as_bytes());
+                    if sender.send_data(heartbeat).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    (
+        StatusCode::OK,
+        [("content-type", format.content_type())],
+        body::boxed(body),
+    )
+        .into_response()
+}
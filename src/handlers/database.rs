@@ -1,8 +1,11 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use crate::constdb::Engine;
+use crate::handlers::changes::db_changes_route;
 use crate::handlers::models::*;
-use axum::extract::{Path, State};
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::routing::get;
@@ -15,6 +18,11 @@ pub fn db_routes() -> Router<Arc<RwLock<Engine>>> {
         .route("/", get(list_db_route))
         .route("/", post(create_db_route))
         .route("/:db_name", delete(drop_db_route))
+        .route("/:db_name/clone", post(clone_db_route))
+        .route("/:db_name/transaction", post(transaction_route))
+        .route("/:db_name/snapshots", post(create_snapshot_route))
+        .route("/:db_name/snapshots/:token", delete(release_snapshot_route))
+        .route("/:db_name/changes", get(db_changes_route))
 }
 
 pub async fn list_db_route(State(const_db): State<Arc<RwLock<Engine>>>) -> impl IntoResponse {
@@ -50,3 +58,59 @@ pub async fn drop_db_route(
         Err(e) => (e.http_status_code(), e.to_string()).into_response(),
     }
 }
+
+pub async fn clone_db_route(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path(db_name): Path<String>,
+    Json(clone_db_input): Json<CloneDBInput>,
+) -> impl IntoResponse {
+    println!("cloning db [{}] to [{}]...", db_name, clone_db_input.name);
+    let mut cdb = const_db.write().await;
+    let result = cdb.clone_db(db_name.as_str(), clone_db_input.name.as_str());
+    match result {
+        Ok(db) => (StatusCode::CREATED, Json(db)).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
+
+pub async fn transaction_route(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path(db_name): Path<String>,
+    bytes: Bytes,
+) -> impl IntoResponse {
+    let cdb = const_db.read().await;
+    let result = cdb.transaction(db_name.as_str(), bytes);
+    match result {
+        Ok(v) => (StatusCode::OK, [("content-type", "application/json")], v).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
+
+pub async fn create_snapshot_route(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path(db_name): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let cdb = const_db.read().await;
+    let result = cdb.create_snapshot(db_name.as_str(), &params);
+    match result {
+        Ok((token, ttl)) => (
+            StatusCode::CREATED,
+            Json(serde_json::json!({ "token": token, "ttl": ttl })),
+        )
+            .into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
+
+pub async fn release_snapshot_route(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path((db_name, token)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let cdb = const_db.read().await;
+    let result = cdb.release_snapshot(db_name.as_str(), token.as_str());
+    match result {
+        Ok(()) => StatusCode::OK.into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
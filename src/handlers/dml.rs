@@ -1,23 +1,41 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 
-use crate::constdb::Engine;
+use crate::constdb::errors::ConstDBError;
+use crate::constdb::scan::ScanOptions;
 
-use axum::body::Bytes;
+use crate::constdb::{Engine, HeldSnapshot, Id, Precondition};
+use crate::handlers::changes::table_changes_route;
+
+use axum::body::{self, Body, Bytes};
 use axum::extract::{Path, Query, State};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::routing::post;
-use axum::routing::{delete, get, put};
+use axum::routing::{delete, get, patch, put};
 use axum::Router;
+use tokio::runtime::Handle;
 use tokio::sync::RwLock;
 
+/// rows are flushed to the client in chunks of about this size during a full table scan
+const SCAN_CHUNK_SIZE: usize = 64 * 1024;
+/// a full table scan reads this many rows at a time from the engine
+const SCAN_PAGE_SIZE: usize = 1000;
+
 pub fn dml_routes() -> Router<Arc<RwLock<Engine>>> {
     Router::new()
         .route("/", post(table_insert))
         .route("/", get(table_get_by_key))
         .route("/", delete(table_delete))
         .route("/", put(table_upsert))
+        .route("/", patch(table_patch))
+        .route("/batch", post(table_batch))
+        .route("/update", post(table_update))
+        .route("/cas", post(table_compare_and_swap))
+        .route("/history", get(table_row_history))
+        .route("/changes", get(table_changes_route))
+        .route("/watch", get(table_watch))
 }
 
 pub async fn table_insert(
@@ -39,23 +57,154 @@ pub async fn table_get_by_key(
     Path((db_name, table_name)): Path<(String, String)>,
     Query(params): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
+    if params.get("scan").map(|scan| scan.as_str()) == Some("all") {
+        return table_scan(const_db, db_name, table_name, params).await;
+    }
     let const_db = Arc::clone(&const_db);
     let cdb = const_db.read().await;
     let result = cdb.query_by_key(db_name.as_str(), table_name.as_str(), params);
     match result {
-        Ok(v) => (StatusCode::OK, [("content-type", "aplication/json")], v).into_response(),
+        Ok((v, Some(etag))) => (
+            StatusCode::OK,
+            [("content-type", "aplication/json"), ("etag", etag.as_str())],
+            v,
+        )
+            .into_response(),
+        Ok((v, None)) => (StatusCode::OK, [("content-type", "aplication/json")], v).into_response(),
         Err(e) => (e.http_status_code(), e.to_string()).into_response(),
     }
 }
 
+/// stream all the rows of the table as `{"rows": [...], "next_cursor": ...}`. the rows are read
+/// a page at a time at a snapshot held by the scan, and the engine is never locked while the rows
+/// are sent.
+async fn table_scan(
+    const_db: Arc<RwLock<Engine>>,
+    db_name: String,
+    table_name: String,
+    params: HashMap<String, String>,
+) -> Response {
+    let result = {
+        let cdb = const_db.read().await;
+        cdb.full_scan_options(db_name.as_str(), table_name.as_str(), &params)
+            .and_then(|options| {
+                let snapshot = cdb.hold_snapshot(db_name.as_str(), options.snapshot.as_deref())?;
+                Ok((options, snapshot))
+            })
+    };
+    let (options, snapshot) = match result {
+        Ok(result) => result,
+        Err(e) => return (e.http_status_code(), e.to_string()).into_response(),
+    };
+
+    let (mut sender, body) = Body::channel();
+    tokio::task::spawn_blocking(move || {
+        let handle = Handle::current();
+        let token = options.snapshot.clone();
+        let send = |chunk: String| {
+            // the snapshot of the token is kept for the client while the rows are sent, the scan
+            // holds it itself, so it goes on even if the token is released meanwhile
+            if let Some(token) = &token {
+                const_db.blocking_read().keep_snapshot(&db_name, token).ok();
+            }
+            handle
+                .block_on(sender.send_data(Bytes::from(chunk)))
+                .map_err(|e| ConstDBError::InvalidStates(format!("scan aborted: {}", e)))
+        };
+        let result = scan_pages(&const_db, &db_name, &table_name, options, &snapshot, send);
+        if let Err(e) = result {
+            println!(
+                "full scan of table [{}.{}] failed: {}",
+                db_name,
+                table_name,
+                e.to_string()
+            );
+            sender.abort();
+        }
+    });
+    (
+        StatusCode::OK,
+        [("content-type", "application/json")],
+        body::boxed(body),
+    )
+        .into_response()
+}
+
+/// read the rows a page at a time, the engine is locked while reading a page only
+fn scan_pages<F>(
+    const_db: &RwLock<Engine>,
+    db_name: &str,
+    table_name: &str,
+    options: ScanOptions,
+    snapshot: &HeldSnapshot,
+    mut send: F,
+) -> Result<(), ConstDBError>
+where
+    F: FnMut(String) -> Result<(), ConstDBError>,
+{
+    let mut remaining = options.limit;
+    let mut page_options = ScanOptions {
+        limit: remaining.min(SCAN_PAGE_SIZE),
+        ..options
+    };
+    let mut chunk = String::from("{\"rows\":[");
+    let mut first_row = true;
+    let next_cursor = loop {
+        let mut rows = Vec::new();
+        let next_cursor = const_db.blocking_read().full_scan(
+            db_name,
+            table_name,
+            page_options.clone(),
+            snapshot,
+            |row| {
+                rows.push(row);
+                Ok(())
+            },
+        )?;
+        remaining -= rows.len();
+        for row in rows {
+            if !first_row {
+                chunk.push(',');
+            }
+            first_row = false;
+            chunk.push_str(row.as_str());
+            if chunk.len() >= SCAN_CHUNK_SIZE {
+                send(std::mem::take(&mut chunk))?;
+            }
+        }
+        match next_cursor {
+            Some(cursor) if remaining > 0 => {
+                page_options = page_options.next_page(remaining.min(SCAN_PAGE_SIZE), &cursor)?
+            }
+            next_cursor => break next_cursor,
+        }
+    };
+    chunk.push_str("],\"next_cursor\":");
+    chunk.push_str(serde_json::to_string(&next_cursor)?.as_str());
+    chunk.push('}');
+    send(chunk)
+}
+
+/// the `If-Match` and `If-None-Match` of the request
+fn precondition(headers: &HeaderMap) -> Precondition {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    Precondition::new(header(header::IF_MATCH), header(header::IF_NONE_MATCH))
+}
+
 pub async fn table_delete(
     State(const_db): State<Arc<RwLock<Engine>>>,
     Path((db_name, table_name)): Path<(String, String)>,
     Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
     let const_db = Arc::clone(&const_db);
     let cdb = const_db.read().await;
-    let result = cdb.delete(db_name.as_str(), table_name.as_str(), params);
+    let result = cdb.delete(
+        db_name.as_str(),
+        table_name.as_str(),
+        params,
+        &precondition(&headers),
+    );
     match result {
         Ok(()) => (StatusCode::OK, ()).into_response(),
         Err(e) => (e.http_status_code(), e.to_string()).into_response(),
@@ -65,13 +214,147 @@ pub async fn table_delete(
 pub async fn table_upsert(
     State(const_db): State<Arc<RwLock<Engine>>>,
     Path((db_name, table_name)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    bytes: Bytes,
+) -> impl IntoResponse {
+    let const_db = Arc::clone(&const_db);
+    let cdb = const_db.read().await;
+    let result = cdb.upsert(
+        db_name.as_str(),
+        table_name.as_str(),
+        params,
+        &precondition(&headers),
+        bytes,
+    );
+    match result {
+        Ok(()) => StatusCode::OK.into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
+
+pub async fn table_patch(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path((db_name, table_name)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
     bytes: Bytes,
 ) -> impl IntoResponse {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
     let const_db = Arc::clone(&const_db);
     let cdb = const_db.read().await;
-    let result = cdb.upsert(db_name.as_str(), table_name.as_str(), bytes);
+    let result = cdb.patch(
+        db_name.as_str(),
+        table_name.as_str(),
+        params,
+        &precondition(&headers),
+        content_type,
+        bytes,
+    );
     match result {
         Ok(()) => StatusCode::OK.into_response(),
         Err(e) => (e.http_status_code(), e.to_string()).into_response(),
     }
 }
+
+pub async fn table_update(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path((db_name, table_name)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    bytes: Bytes,
+) -> impl IntoResponse {
+    let const_db = Arc::clone(&const_db);
+    let cdb = const_db.read().await;
+    let result = cdb.update(db_name.as_str(), table_name.as_str(), params, bytes);
+    match result {
+        Ok(v) => (StatusCode::OK, [("content-type", "application/json")], v).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
+
+pub async fn table_compare_and_swap(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path((db_name, table_name)): Path<(String, String)>,
+    bytes: Bytes,
+) -> impl IntoResponse {
+    let const_db = Arc::clone(&const_db);
+    let cdb = const_db.read().await;
+    let result = cdb.compare_and_swap(db_name.as_str(), table_name.as_str(), bytes);
+    match result {
+        Ok(v) => (StatusCode::OK, [("content-type", "application/json")], v).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
+
+pub async fn table_row_history(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path((db_name, table_name)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let const_db = Arc::clone(&const_db);
+    let cdb = const_db.read().await;
+    let result = cdb.row_history(db_name.as_str(), table_name.as_str(), params);
+    match result {
+        Ok(v) => (StatusCode::OK, [("content-type", "application/json")], v).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
+
+/// wait for the changes of the rows after the revision, answers `304 Not Modified` if the rows
+/// are not changed within the timeout
+pub async fn table_watch(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path((db_name, table_name)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let result = const_db
+        .read()
+        .await
+        .watch_rows(db_name.as_str(), table_name.as_str(), &params);
+    let (mut watch, mut receiver) = match result {
+        Ok(watch) => watch,
+        Err(e) => return (e.http_status_code(), e.to_string()).into_response(),
+    };
+    let deadline = Instant::now() + watch.timeout;
+    loop {
+        // marked as seen before reading, so that the changes committed meanwhile wake it up
+        receiver.borrow_and_update();
+        let result =
+            const_db
+                .read()
+                .await
+                .poll_watch(db_name.as_str(), table_name.as_str(), &mut watch);
+        match result {
+            Ok(Some(v)) => {
+                return (StatusCode::OK, [("content-type", "application/json")], v).into_response()
+            }
+            Ok(None) => {}
+            Err(e) => return (e.http_status_code(), e.to_string()).into_response(),
+        }
+        match tokio::time::timeout_at(deadline.into(), receiver.changed()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => {
+                let e = ConstDBError::NotFound(Id::Database(db_name));
+                return (e.http_status_code(), e.to_string()).into_response();
+            }
+            Err(_) => return (StatusCode::NOT_MODIFIED, ()).into_response(),
+        }
+    }
+}
+
+pub async fn table_batch(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path((db_name, table_name)): Path<(String, String)>,
+    bytes: Bytes,
+) -> impl IntoResponse {
+    let const_db = Arc::clone(&const_db);
+    let cdb = const_db.read().await;
+    let result = cdb.write_batch(db_name.as_str(), table_name.as_str(), bytes);
+    match result {
+        Ok(v) => (StatusCode::OK, [("content-type", "application/json")], v).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
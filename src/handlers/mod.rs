@@ -1,3 +1,5 @@
+pub mod backup;
+pub mod changes;
 pub mod database;
 pub mod dml;
 pub mod models;
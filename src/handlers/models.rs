@@ -10,6 +10,11 @@ pub struct CreateDBInput {
     pub name: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CloneDBInput {
+    pub name: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateDBOutput {
     pub name: String,
@@ -2,12 +2,12 @@ use std::sync::Arc;
 
 use crate::constdb::Engine;
 use crate::handlers::models::*;
-use crate::protos::constdb_model::TableSettings;
+use crate::protos::constdb_model::{Index, TableSettings};
 
 use axum::extract::{Path, State};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
-use axum::routing::{delete, get, post};
+use axum::routing::{delete, get, patch, post};
 use axum::{Json, Router};
 use tokio::sync::RwLock;
 
@@ -16,6 +16,9 @@ pub fn table_routes() -> Router<Arc<RwLock<Engine>>> {
         .route("/", get(list_table_route))
         .route("/", post(create_table_route))
         .route("/:table_name", delete(drop_table_route))
+        .route("/:table_name", patch(alter_table_route))
+        .route("/:table_name/indexes", post(create_index_route))
+        .route("/:table_name/indexes/:index_name", delete(drop_index_route))
 }
 
 pub async fn list_table_route(
@@ -63,3 +66,46 @@ pub async fn drop_table_route(
         Err(e) => (e.http_status_code(), e.to_string()).into_response(),
     }
 }
+
+pub async fn alter_table_route(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path((db_name, table_name)): Path<(String, String)>,
+    Json(alteration): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    println!("alter table [{}] under db [{}]", table_name, db_name);
+    let mut cdb = const_db.write().await;
+    let result = cdb.alter_table(db_name.as_str(), table_name.as_str(), alteration);
+    match result {
+        Ok(table) => (StatusCode::OK, Json(table)).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
+
+pub async fn create_index_route(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path((db_name, table_name)): Path<(String, String)>,
+    Json(index): Json<Index>,
+) -> impl IntoResponse {
+    println!(
+        "create index [{}] on table [{}] under db [{}]",
+        index.name, table_name, db_name
+    );
+    let mut cdb = const_db.write().await;
+    let result = cdb.create_index(db_name.as_str(), table_name.as_str(), &index);
+    match result {
+        Ok(table) => (StatusCode::CREATED, Json(table)).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
+
+pub async fn drop_index_route(
+    State(const_db): State<Arc<RwLock<Engine>>>,
+    Path((db_name, table_name, index_name)): Path<(String, String, String)>,
+) -> impl IntoResponse {
+    let mut cdb = const_db.write().await;
+    let result = cdb.drop_index(db_name.as_str(), table_name.as_str(), index_name.as_str());
+    match result {
+        Ok(table) => (StatusCode::OK, Json(table)).into_response(),
+        Err(e) => (e.http_status_code(), e.to_string()).into_response(),
+    }
+}
@@ -1,6 +1,7 @@
 pub mod constdb;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 mod handlers;
 mod protos;
@@ -9,17 +10,26 @@ use axum::ServiceExt;
 
 use axum::routing::get;
 use axum::Router;
-use constdb::{Engine, Settings};
+use constdb::errors::ConstDBError;
+use constdb::{Backups, Engine, Settings};
+use handlers::backup::backup_routes;
 use handlers::database::db_routes;
 use handlers::dml::dml_routes;
 use handlers::table::table_routes;
 
 use tokio::sync::RwLock;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use tower::layer::Layer;
 use tower_http::normalize_path::NormalizePathLayer;
 
+/// idle snapshots are released at this interval
+const SNAPSHOT_REAP_INTERVAL: Duration = Duration::from_secs(10);
+/// the versions of rows beyond the retention of the row history are pruned at this interval
+const ROW_HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+/// the changes beyond the retention of the change logs are pruned at this interval
+const CHANGE_LOG_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
 /// The constdb app
 #[derive(Debug, Parser)]
 #[clap(author, version, about, long_about=None)]
@@ -27,6 +37,47 @@ struct ConstDBArgs {
     /// Path to the root folder of constdb
     #[clap(short, long, action)]
     root: String,
+    /// Max number of rows returned by a single scan
+    #[clap(long, default_value_t = 1000)]
+    max_page_size: usize,
+    /// Max number of rows streamed by a single full table scan
+    #[clap(long, default_value_t = 100000)]
+    max_scan_size: usize,
+    /// Max seconds a snapshot is kept without being used
+    #[clap(long, default_value_t = 3600)]
+    max_snapshot_ttl: u64,
+    /// Seconds the changes of the rows are kept in the change logs
+    #[clap(long, default_value_t = 86400)]
+    change_retention_secs: u64,
+    /// Path to the folder of the backups
+    #[clap(long)]
+    backup_dir: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// the commands run against the root instead of serving it, while the server is not running
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Back up all the databases incrementally
+    Backup,
+    /// List the backups
+    ListBackups,
+    /// Purge the old backups and keep the latest ones
+    PurgeBackups {
+        /// Number of the latest backups to keep
+        #[clap(long)]
+        keep: usize,
+    },
+    /// Restore the whole server, or a database along with its tables, from a backup
+    Restore {
+        /// Id of the backup, the latest backup if not given
+        #[clap(long)]
+        backup_id: Option<u32>,
+        /// Database to restore, all the databases if not given
+        #[clap(long)]
+        db: Option<String>,
+    },
 }
 
 #[tokio::main]
@@ -34,12 +85,31 @@ async fn main() {
     let args = ConstDBArgs::parse();
     let settings = Settings {
         root: args.root.to_string(),
+        max_page_size: args.max_page_size,
+        max_scan_size: args.max_scan_size,
+        max_snapshot_ttl: args.max_snapshot_ttl,
+        change_retention_secs: args.change_retention_secs,
+        backup_dir: args.backup_dir,
     };
+    if let Some(command) = args.command {
+        match run_command(settings, command) {
+            Ok(output) => println!("{}", output),
+            Err(e) => {
+                println!("{}", e.to_string());
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
     let const_db = Arc::new(RwLock::new(Engine::new(settings).unwrap()));
+    tokio::spawn(reap_snapshots(Arc::clone(&const_db)));
+    tokio::spawn(prune_row_history(Arc::clone(&const_db)));
+    tokio::spawn(prune_changes(Arc::clone(&const_db)));
 
     let router = Router::new()
         .route("/", get(root))
         .nest("/api/v1/dbs", db_routes())
+        .nest("/api/v1/backups", backup_routes())
         .nest("/api/v1/dbs/:db_name/tables", table_routes())
         .nest("/api/v1/dbs/:db_name/tables/:table_name/data", dml_routes())
         .with_state(const_db);
@@ -53,6 +123,86 @@ async fn main() {
         .unwrap();
 }
 
+/// run the command, returns the backups of the command as json
+fn run_command(settings: Settings, command: Command) -> Result<String, ConstDBError> {
+    let output = match command {
+        Command::Backup => serde_json::to_string_pretty(&Engine::new(settings)?.create_backup()?)?,
+        Command::ListBackups => {
+            serde_json::to_string_pretty(&Engine::new(settings)?.list_backups()?)?
+        }
+        Command::PurgeBackups { keep } => {
+            serde_json::to_string_pretty(&Engine::new(settings)?.purge_backups(keep)?)?
+        }
+        Command::Restore {
+            backup_id,
+            db: Some(db_name),
+        } => {
+            let backup = Engine::new(settings)?.restore_db(backup_id, db_name.as_str())?;
+            serde_json::to_string_pretty(&backup)?
+        }
+        Command::Restore {
+            backup_id,
+            db: None,
+        } => {
+            let backup_dir = settings.backup_dir.as_deref().ok_or_else(|| {
+                ConstDBError::InvalidArguments("--backup-dir is missing".to_owned())
+            })?;
+            let backups = Backups::new(backup_dir);
+            let backup = match backup_id {
+                Some(backup_id) => backups.get(backup_id)?,
+                None => backups.latest()?,
+            };
+            let root = std::path::Path::new(settings.root.as_str());
+            std::fs::create_dir_all(root)?;
+            backups.restore_server(&backup, root)?;
+            serde_json::to_string_pretty(&backup)?
+        }
+    };
+    Ok(output)
+}
+
+/// release the snapshots that are not used for their ttl
+async fn reap_snapshots(const_db: Arc<RwLock<Engine>>) {
+    let mut interval = tokio::time::interval(SNAPSHOT_REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = const_db.read().await.reap_snapshots() {
+            println!("failed to release idle snapshots: {}", e.to_string());
+        }
+    }
+}
+
+/// prune the versions of rows beyond the retention of the row history
+async fn prune_row_history(const_db: Arc<RwLock<Engine>>) {
+    let mut interval = tokio::time::interval(ROW_HISTORY_PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let cdb = Arc::clone(&const_db).read_owned().await;
+        // the whole row history is scanned, which should not hold up the runtime
+        let result = tokio::task::spawn_blocking(move || cdb.prune_row_history()).await;
+        match result {
+            Ok(Err(e)) => println!("failed to prune row history: {}", e.to_string()),
+            Err(e) => println!("failed to prune row history: {}", e),
+            Ok(Ok(())) => {}
+        }
+    }
+}
+
+/// prune the changes beyond the retention of the change logs
+async fn prune_changes(const_db: Arc<RwLock<Engine>>) {
+    let mut interval = tokio::time::interval(CHANGE_LOG_PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let cdb = Arc::clone(&const_db).read_owned().await;
+        let result = tokio::task::spawn_blocking(move || cdb.prune_changes()).await;
+        match result {
+            Ok(Err(e)) => println!("failed to prune changes: {}", e.to_string()),
+            Err(e) => println!("failed to prune changes: {}", e),
+            Ok(Ok(())) => {}
+        }
+    }
+}
+
 async fn root() -> &'static str {
     "Hello, ConstDB!"
 }
@@ -1,6 +1,6 @@
 use std::{
     io::{Error, ErrorKind},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 pub fn exists<P: AsRef<Path>>(p: P) -> Result<bool, Error> {
@@ -10,3 +10,82 @@ pub fn exists<P: AsRef<Path>>(p: P) -> Result<bool, Error> {
         Err(e) => Err(e),
     }
 }
+
+/// the path next to the path, with the suffix appended to its name
+pub fn sibling<P: AsRef<Path>>(p: P, suffix: &str) -> PathBuf {
+    let p = p.as_ref();
+    let mut name = p.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    p.with_file_name(name)
+}
+
+/// remove the directory if it exists
+pub fn remove_dir<P: AsRef<Path>>(p: P) -> Result<(), Error> {
+    match std::fs::remove_dir_all(p) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// move the directory aside to be replaced, returns where it is moved to if it exists. the
+/// directory is moved back by `move_back`, or removed once replaced.
+pub fn move_aside<P: AsRef<Path>>(p: P) -> Result<Option<PathBuf>, Error> {
+    let p = p.as_ref();
+    if !exists(p)? {
+        return Ok(None);
+    }
+    let replaced = sibling(p, "replaced");
+    remove_dir(&replaced)?;
+    std::fs::rename(p, &replaced)?;
+    Ok(Some(replaced))
+}
+
+/// move the directory moved aside by `move_aside` back, the replacement is removed
+pub fn move_back<P: AsRef<Path>>(p: P, replaced: Option<PathBuf>) -> Result<(), Error> {
+    remove_dir(&p)?;
+    match replaced {
+        Some(replaced) => std::fs::rename(replaced, p),
+        None => Ok(()),
+    }
+}
+
+/// replace the directory by another one
+pub fn replace_dir<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<(), Error> {
+    let replaced = move_aside(&to)?;
+    if let Err(e) = std::fs::rename(from, &to) {
+        move_back(&to, replaced)?;
+        return Err(e);
+    }
+    match replaced {
+        Some(replaced) => remove_dir(replaced),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directories_are_replaced_or_moved_back() {
+        let root = std::env::temp_dir().join(format!("constdb-fs-{}", std::process::id()));
+        let (from, to) = (root.join("from"), root.join("to"));
+        std::fs::create_dir_all(&from).unwrap();
+        std::fs::create_dir_all(&to).unwrap();
+        std::fs::write(from.join("new"), b"").unwrap();
+        std::fs::write(to.join("old"), b"").unwrap();
+
+        let replaced = move_aside(&to).unwrap();
+        assert_eq!(replaced, Some(sibling(&to, "replaced")));
+        std::fs::create_dir_all(&to).unwrap();
+        move_back(&to, replaced).unwrap();
+        assert!(exists(to.join("old")).unwrap());
+
+        replace_dir(&from, &to).unwrap();
+        assert!(exists(to.join("new")).unwrap());
+        assert!(!exists(&from).unwrap());
+        assert!(!exists(sibling(&to, "replaced")).unwrap());
+        remove_dir(&root).unwrap();
+    }
+}